@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use log::warn;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+use crate::config::{Account, ImportSource};
+use crate::subject::{has_same_wildcards, is_valid_subscription_subject, subject_is_subset};
+
+// replies of service imports are published by the exporting account on `_R_.<random>`
+const SERVICE_REPLY_PREFIX: &str = "_R_.";
+const SERVICE_REPLY_LENGTH: usize = 12;
+// service replies that never arrive are forgotten after this long
+pub const SERVICE_REPLY_TTL: Duration = Duration::from_secs(120);
+
+// messages published by the exporting account on `subject` are delivered to `importer` on `to`
+#[derive(Debug, PartialEq, Eq)]
+pub struct StreamImport {
+    pub importer: String,
+    pub subject: String,
+    pub to: String,
+}
+
+// requests published by the importing account on `to` are forwarded to `exporter` on `subject`
+#[derive(Debug, PartialEq, Eq)]
+pub struct ServiceImport {
+    pub exporter: String,
+    pub subject: String,
+    pub to: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Imports {
+    // keyed by the exporting account
+    pub streams: HashMap<String, Vec<StreamImport>>,
+    // keyed by the importing account
+    pub services: HashMap<String, Vec<ServiceImport>>,
+}
+
+// where the reply of a forwarded service request goes back to
+#[derive(Debug)]
+pub struct ServiceReply {
+    pub exporter: String,
+    pub importer: String,
+    pub reply_to: String,
+    pub created: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ImportKind {
+    Stream,
+    Service,
+}
+
+impl Imports {
+    // imports without a matching export are logged and ignored
+    pub fn new(accounts: &HashMap<String, Account>) -> Self {
+        let mut imports = Imports::default();
+        for (importer, account) in accounts {
+            for import in &account.imports {
+                let (kind, source) = match (&import.stream, &import.service) {
+                    (Some(source), None) => (ImportKind::Stream, source),
+                    (None, Some(source)) => (ImportKind::Service, source),
+                    _ => {
+                        warn!("account {} has an import that is not exactly one of stream or service", importer);
+                        continue;
+                    }
+                };
+                let to = import.to.clone().unwrap_or_else(|| source.subject.clone());
+                if !is_valid_subscription_subject(&source.subject) || !is_valid_subscription_subject(&to) || !has_same_wildcards(&source.subject, &to) {
+                    warn!("account {} cannot import {} as {}", importer, source.subject, to);
+                    continue;
+                }
+                if !is_exported(accounts, kind, source, importer) {
+                    warn!("account {} imports {:?} {} which account {} does not export to it", importer, kind, source.subject, source.account);
+                    continue;
+                }
+                match kind {
+                    ImportKind::Stream => imports.streams.entry(source.account.clone()).or_default()
+                        .push(StreamImport { importer: importer.clone(), subject: source.subject.clone(), to }),
+                    ImportKind::Service => imports.services.entry(importer.clone()).or_default()
+                        .push(ServiceImport { exporter: source.account.clone(), subject: source.subject.clone(), to }),
+                }
+            }
+        }
+        imports
+    }
+}
+
+fn is_exported(accounts: &HashMap<String, Account>, kind: ImportKind, source: &ImportSource, importer: &String) -> bool {
+    let Some(exporter) = accounts.get(&source.account) else {
+        return false;
+    };
+    exporter.exports.iter().any(|export| {
+        let subject = match kind {
+            ImportKind::Stream => export.stream.as_ref(),
+            ImportKind::Service => export.service.as_ref(),
+        };
+        subject.is_some_and(|subject| subject_is_subset(&source.subject, subject))
+            && (export.accounts.is_empty() || export.accounts.contains(importer))
+    })
+}
+
+pub fn generate_service_reply() -> String {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SERVICE_REPLY_LENGTH)
+        .map(char::from)
+        .collect();
+    format!("{}{}", SERVICE_REPLY_PREFIX, token)
+}
+
+pub fn is_service_reply(subject: &str) -> bool {
+    subject.starts_with(SERVICE_REPLY_PREFIX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+
+    const ACCOUNTS: &str = r#"
+        listeners = [{ address = "127.0.0.1:4222" }]
+
+        [accounts.team_a]
+        exports = [
+            { stream = "events.>" },
+            { service = "help", accounts = ["team_b"] },
+        ]
+
+        [accounts.team_b]
+        imports = [
+            { stream = { account = "team_a", subject = "events.orders.>" }, to = "a.orders.>" },
+            { service = { account = "team_a", subject = "help" }, to = "a.help" },
+        ]
+
+        [accounts.team_c]
+        imports = [
+            { stream = { account = "team_a", subject = "events.>" } },
+            { service = { account = "team_a", subject = "help" } },
+            { stream = { account = "team_a", subject = "secret.>" } },
+            { stream = { account = "team_a", subject = "events.*" }, to = "a.>" },
+            { stream = { account = "team_b", subject = "events.>" } },
+        ]
+    "#;
+
+    fn imports() -> Imports {
+        let config: Config = toml::from_str(ACCOUNTS).unwrap();
+        Imports::new(&config.accounts)
+    }
+
+    #[test]
+    fn test_stream_imports() {
+        let mut streams = imports().streams.remove("team_a").unwrap();
+        streams.sort_by(|a, b| a.importer.cmp(&b.importer));
+        assert_eq!(vec![
+            StreamImport { importer: "team_b".to_string(), subject: "events.orders.>".to_string(), to: "a.orders.>".to_string() },
+            StreamImport { importer: "team_c".to_string(), subject: "events.>".to_string(), to: "events.>".to_string() },
+        ], streams);
+    }
+
+    #[test]
+    fn test_service_imports() {
+        let imports = imports();
+        assert_eq!(&vec![ServiceImport { exporter: "team_a".to_string(), subject: "help".to_string(), to: "a.help".to_string() }],
+            imports.services.get("team_b").unwrap());
+        // team_c is not in the accounts allowed to import the service
+        assert!(!imports.services.contains_key("team_c"));
+    }
+
+    #[test]
+    fn test_imports_from_account_without_exports() {
+        assert!(!imports().streams.contains_key("team_b"));
+    }
+
+    #[test]
+    fn test_generate_service_reply() {
+        let reply = generate_service_reply();
+        assert!(is_service_reply(&reply));
+        assert_ne!(reply, generate_service_reply());
+    }
+}
@@ -0,0 +1,440 @@
+use std::error::Error;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bcrypt::BcryptError;
+use log::{debug, warn};
+use nkeys::KeyPair;
+use rand::Rng;
+use crate::config::{Authorization, LinkAuthorization, Permissions, SubjectPermission};
+use crate::jwt::{verify_user_jwt, DirResolver, JwtError};
+use crate::parser::ClientConnectOpts;
+use crate::subject::subject_is_subset;
+
+const BCRYPT_PREFIX: &str = "$2";
+const NONCE_SIZE: usize = 11;
+
+// identity of an authenticated client, permissions are only set for restricted users,
+// connection limit and expiry only for JWT users
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ClientAuth {
+    pub user: Option<String>,
+    pub permissions: Option<Permissions>,
+    pub account: Option<String>,
+    pub max_connections: Option<u32>,
+    pub expires: Option<u64>,
+}
+
+impl Authorization {
+    pub fn is_required(&self) -> bool {
+        self.user.is_some() || self.token.is_some() || !self.users.is_empty() || self.is_nonce_required()
+    }
+
+    // a nonce is only sent in INFO when nkey or JWT users are configured
+    pub fn is_nonce_required(&self) -> bool {
+        !self.nkeys.is_empty() || !self.trusted_keys.is_empty()
+    }
+
+    pub fn authenticate(&self, opts: &ClientConnectOpts, nonce: Option<&str>) -> Option<ClientAuth> {
+        if !self.is_required() {
+            return Some(ClientAuth::default());
+        }
+
+        if let Some(jwt) = &opts.jwt {
+            return self.authenticate_jwt(jwt, opts.sig.as_deref(), nonce);
+        }
+
+        if let Some(nkey) = &opts.nkey {
+            return self.authenticate_nkey(nkey, opts.sig.as_deref(), nonce);
+        }
+
+        if let (Some(token), Some(auth_token)) = (&self.token, &opts.auth_token) {
+            if verify_password(token, auth_token) {
+                debug!("client authenticated with token");
+                return Some(ClientAuth::default());
+            }
+        }
+
+        let (Some(user), Some(pass)) = (&opts.user, &opts.pass) else {
+            warn!("client did not provide valid credentials");
+            return None;
+        };
+
+        if self.user.as_ref() == Some(user) && self.password.as_ref().is_some_and(|password| verify_password(password, pass)) {
+            debug!("client authenticated as {}", user);
+            return Some(ClientAuth { user: Some(user.clone()), ..Default::default() });
+        }
+
+        if let Some(u) = self.users.iter().find(|u| &u.user == user && u.password.as_ref().is_some_and(|password| verify_password(password, pass))) {
+            debug!("client authenticated as {}", user);
+            return Some(ClientAuth { user: Some(user.clone()), permissions: u.permissions.clone(), account: u.account.clone(), ..Default::default() });
+        }
+
+        warn!("client failed to authenticate as {}", user);
+        None
+    }
+
+    // clients with a verified certificate are mapped to the first user matching one of its identities
+    pub fn authenticate_cert(&self, cert_identities: &[String]) -> Option<ClientAuth> {
+        let user = cert_identities.iter()
+            .find_map(|identity| self.users.iter().find(|u| &u.user == identity));
+        let Some(user) = user else {
+            warn!("no user for client certificate {:?}", cert_identities);
+            return None;
+        };
+        debug!("client authenticated with certificate as {}", user.user);
+        Some(ClientAuth { user: Some(user.user.clone()), permissions: user.permissions.clone(), account: user.account.clone(), ..Default::default() })
+    }
+
+    fn authenticate_nkey(&self, nkey: &str, sig: Option<&str>, nonce: Option<&str>) -> Option<ClientAuth> {
+        let Some(nkey_user) = self.nkeys.iter().find(|u| u.nkey == nkey) else {
+            warn!("unknown nkey {}", nkey);
+            return None;
+        };
+        let (Some(sig), Some(nonce)) = (sig, nonce) else {
+            warn!("nkey {} did not sign the nonce", nkey);
+            return None;
+        };
+        if let Err(e) = verify_signature(nkey, nonce.as_bytes(), sig) {
+            warn!("nkey {} failed signature verification: {}", nkey, e);
+            return None;
+        }
+        debug!("client authenticated with nkey {}", nkey);
+        Some(ClientAuth {
+            user: Some(nkey.to_string()),
+            permissions: nkey_user.permissions.clone(),
+            account: nkey_user.account.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn authenticate_jwt(&self, jwt: &str, sig: Option<&str>, nonce: Option<&str>) -> Option<ClientAuth> {
+        if self.trusted_keys.is_empty() {
+            warn!("client sent a JWT but no trusted keys are configured");
+            return None;
+        }
+        let verified = self.resolver.as_ref()
+            .ok_or(JwtError::NoResolver)
+            .and_then(|resolver| verify_user_jwt(jwt, &self.trusted_keys, &DirResolver::new(&resolver.dir)));
+        let verified = match verified {
+            Ok(verified) => verified,
+            Err(e) => {
+                warn!("client failed JWT verification: {}", e);
+                return None;
+            }
+        };
+
+        let user = &verified.user.sub;
+        if !verified.user.nats.bearer_token {
+            let (Some(sig), Some(nonce)) = (sig, nonce) else {
+                warn!("JWT user {} did not sign the nonce", user);
+                return None;
+            };
+            if let Err(e) = verify_signature(user, nonce.as_bytes(), sig) {
+                warn!("JWT user {} failed signature verification: {}", user, e);
+                return None;
+            }
+        }
+        debug!("client authenticated as JWT user {} of account {}", user, verified.account_id());
+        Some(ClientAuth {
+            user: Some(user.clone()),
+            permissions: verified.user.nats.permissions(),
+            account: Some(verified.account_id().to_string()),
+            max_connections: verified.account.nats.max_connections(),
+            expires: verified.expires(),
+        })
+    }
+}
+
+pub fn generate_nonce() -> String {
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill(&mut nonce[..]);
+    URL_SAFE_NO_PAD.encode(nonce)
+}
+
+// signatures are base64 url encoded by most clients, some send standard base64
+fn verify_signature(public_key: &str, input: &[u8], sig: &str) -> Result<(), Box<dyn Error>> {
+    let sig = URL_SAFE_NO_PAD.decode(sig)
+        .or_else(|_| STANDARD.decode(sig))?;
+    KeyPair::from_public_key(public_key)?.verify(input, &sig)?;
+    Ok(())
+}
+
+// configured passwords may be bcrypt hashes, see `challenge_nats passwd`
+fn verify_password(configured: &str, provided: &str) -> bool {
+    if configured.starts_with(BCRYPT_PREFIX) {
+        return bcrypt::verify(provided, configured).unwrap_or_else(|e| {
+            warn!("error verifying bcrypt password: {}", e);
+            false
+        });
+    }
+    configured == provided
+}
+
+pub fn hash_password(password: &str) -> Result<String, BcryptError> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+}
+
+impl LinkAuthorization {
+    pub fn verify(&self, user: &str, pass: &str) -> bool {
+        self.user == user && verify_password(&self.password, pass)
+    }
+}
+
+impl Permissions {
+    pub fn can_publish(&self, subject: &str) -> bool {
+        self.publish.allows(subject)
+    }
+
+    pub fn can_subscribe(&self, subject: &str) -> bool {
+        self.subscribe.allows(subject)
+    }
+}
+
+impl SubjectPermission {
+    fn allows(&self, subject: &str) -> bool {
+        let allowed = self.allow.as_ref()
+            .map(|allow| allow.iter().any(|pattern| subject_is_subset(subject, pattern)))
+            .unwrap_or(true);
+        allowed && !self.deny.iter().any(|pattern| subject_is_subset(subject, pattern))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{NkeyUser, Resolver, User};
+    use crate::jwt::test::Chain;
+    use serde_json::json;
+    use test_case::test_case;
+
+    const AUTHORIZATION: &str = r#"
+        user = "admin"
+        password = "secret"
+        token = "t0k3n"
+        users = [
+            { user = "alice", password = "a1ice", account = "team_a" },
+            { user = "bob", password = "b0b", permissions = { publish = { allow = ["orders.>"], deny = ["orders.internal.>"] }, subscribe = { deny = ["secret.*"] } } },
+            { user = "CN=carol, O=Acme" },
+            { user = "dave@example.com" },
+        ]
+    "#;
+
+    fn opts(user: Option<&str>, pass: Option<&str>, auth_token: Option<&str>) -> ClientConnectOpts {
+        ClientConnectOpts {
+            user: user.map(str::to_string),
+            pass: pass.map(str::to_string),
+            auth_token: auth_token.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test_case(Some("admin"), Some("secret"), None, true; "single user")]
+    #[test_case(Some("alice"), Some("a1ice"), None, true; "user from list")]
+    #[test_case(Some("bob"), Some("b0b"), None, true; "another user from list")]
+    #[test_case(None, None, Some("t0k3n"), true; "token")]
+    #[test_case(Some("alice"), Some("b0b"), None, false; "wrong password")]
+    #[test_case(Some("carol"), Some("c4rol"), None, false; "unknown user")]
+    #[test_case(Some("admin"), None, None, false; "missing password")]
+    #[test_case(None, None, Some("wrong"), false; "wrong token")]
+    #[test_case(None, None, None, false; "no credentials")]
+    fn test_authenticate(user: Option<&str>, pass: Option<&str>, auth_token: Option<&str>, expected: bool) {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        assert_eq!(expected, authorization.authenticate(&opts(user, pass, auth_token), None).is_some());
+    }
+
+    #[test_case(Some("hashed"), Some("s3cret"), None, true; "bcrypt password")]
+    #[test_case(Some("hashed"), Some("wrong"), None, false; "wrong bcrypt password")]
+    #[test_case(None, None, Some("t0k3n"), true; "bcrypt token")]
+    fn test_authenticate_bcrypt(user: Option<&str>, pass: Option<&str>, auth_token: Option<&str>, expected: bool) {
+        let authorization = Authorization {
+            token: Some(bcrypt::hash("t0k3n", 4).unwrap()),
+            users: vec![User {
+                user: "hashed".to_string(),
+                password: Some(bcrypt::hash("s3cret", 4).unwrap()),
+                permissions: None,
+                account: None,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(expected, authorization.authenticate(&opts(user, pass, auth_token), None).is_some());
+    }
+
+    fn nkey_opts(user: &KeyPair, nonce: &str) -> ClientConnectOpts {
+        ClientConnectOpts {
+            nkey: Some(user.public_key()),
+            sig: Some(URL_SAFE_NO_PAD.encode(user.sign(nonce.as_bytes()).unwrap())),
+            ..Default::default()
+        }
+    }
+
+    fn nkey_authorization(user: &KeyPair) -> Authorization {
+        Authorization {
+            nkeys: vec![NkeyUser { nkey: user.public_key(), permissions: None, account: None }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_authenticate_nkey() {
+        let user = KeyPair::new_user();
+        let nonce = generate_nonce();
+        let authorization = nkey_authorization(&user);
+        assert!(authorization.is_nonce_required());
+        let client_auth = authorization.authenticate(&nkey_opts(&user, &nonce), Some(&nonce)).unwrap();
+        assert_eq!(Some(user.public_key()), client_auth.user);
+    }
+
+    #[test]
+    fn test_authenticate_nkey_standard_base64() {
+        let user = KeyPair::new_user();
+        let nonce = generate_nonce();
+        let mut opts = nkey_opts(&user, &nonce);
+        opts.sig = Some(STANDARD.encode(user.sign(nonce.as_bytes()).unwrap()));
+        assert!(nkey_authorization(&user).authenticate(&opts, Some(&nonce)).is_some());
+    }
+
+    #[test]
+    fn test_authenticate_nkey_wrong_nonce() {
+        let user = KeyPair::new_user();
+        let opts = nkey_opts(&user, "other nonce");
+        assert!(nkey_authorization(&user).authenticate(&opts, Some(&generate_nonce())).is_none());
+    }
+
+    #[test]
+    fn test_authenticate_nkey_unknown_key() {
+        let user = KeyPair::new_user();
+        let nonce = generate_nonce();
+        let opts = nkey_opts(&KeyPair::new_user(), &nonce);
+        assert!(nkey_authorization(&user).authenticate(&opts, Some(&nonce)).is_none());
+    }
+
+    #[test]
+    fn test_authenticate_nkey_without_signature() {
+        let user = KeyPair::new_user();
+        let nonce = generate_nonce();
+        let mut opts = nkey_opts(&user, &nonce);
+        opts.sig = None;
+        assert!(nkey_authorization(&user).authenticate(&opts, Some(&nonce)).is_none());
+    }
+
+    fn jwt_authorization(chain: &Chain) -> Authorization {
+        Authorization {
+            trusted_keys: chain.trusted_keys(),
+            resolver: Some(Resolver { dir: chain.resolver_dir() }),
+            ..Default::default()
+        }
+    }
+
+    fn jwt_opts(chain: &Chain, jwt: String, nonce: &str) -> ClientConnectOpts {
+        ClientConnectOpts {
+            jwt: Some(jwt),
+            sig: Some(URL_SAFE_NO_PAD.encode(chain.user.sign(nonce.as_bytes()).unwrap())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_authenticate_jwt() {
+        let chain = Chain::new(json!({"type": "account", "limits": {"conn": 5}}));
+        let nonce = generate_nonce();
+        let opts = jwt_opts(&chain, chain.user_jwt(json!({"type": "user", "sub": {"allow": ["foo"]}})), &nonce);
+        let authorization = jwt_authorization(&chain);
+        assert!(authorization.is_nonce_required());
+        let client_auth = authorization.authenticate(&opts, Some(&nonce)).unwrap();
+        assert_eq!(Some(chain.user.public_key()), client_auth.user);
+        assert_eq!(Some(chain.account.public_key()), client_auth.account);
+        assert_eq!(Some(5), client_auth.max_connections);
+        assert!(!client_auth.permissions.unwrap().can_subscribe("bar"));
+    }
+
+    #[test]
+    fn test_authenticate_jwt_wrong_nonce() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let opts = jwt_opts(&chain, chain.user_jwt(json!({"type": "user"})), "other nonce");
+        assert!(jwt_authorization(&chain).authenticate(&opts, Some(&generate_nonce())).is_none());
+    }
+
+    #[test]
+    fn test_authenticate_jwt_bearer_token() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let opts = ClientConnectOpts { jwt: Some(chain.user_jwt(json!({"type": "user", "bearer_token": true}))), ..Default::default() };
+        assert!(jwt_authorization(&chain).authenticate(&opts, Some(&generate_nonce())).is_some());
+    }
+
+    #[test]
+    fn test_authenticate_jwt_without_trusted_keys() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let nonce = generate_nonce();
+        let opts = jwt_opts(&chain, chain.user_jwt(json!({"type": "user"})), &nonce);
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        assert!(authorization.authenticate(&opts, Some(&nonce)).is_none());
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("s3cret").unwrap();
+        assert!(hash.starts_with(BCRYPT_PREFIX));
+        assert!(verify_password(&hash, "s3cret"));
+    }
+
+    #[test]
+    fn test_authenticate_returns_user_permissions() {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        let alice = authorization.authenticate(&opts(Some("alice"), Some("a1ice"), None), None).unwrap();
+        assert_eq!(None, alice.permissions);
+        let bob = authorization.authenticate(&opts(Some("bob"), Some("b0b"), None), None).unwrap();
+        assert_eq!(Some("bob".to_string()), bob.user);
+        assert!(bob.permissions.is_some());
+    }
+
+    #[test]
+    fn test_authenticate_returns_user_account() {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        let alice = authorization.authenticate(&opts(Some("alice"), Some("a1ice"), None), None).unwrap();
+        assert_eq!(Some("team_a".to_string()), alice.account);
+        let bob = authorization.authenticate(&opts(Some("bob"), Some("b0b"), None), None).unwrap();
+        assert_eq!(None, bob.account);
+    }
+
+    #[test_case(&["CN=carol, O=Acme", "carol@example.com"], Some("CN=carol, O=Acme"); "subject")]
+    #[test_case(&["CN=dave", "dave@example.com"], Some("dave@example.com"); "alternative name")]
+    #[test_case(&["CN=mallory"], None; "unknown")]
+    fn test_authenticate_cert(cert_identities: &[&str], expected: Option<&str>) {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        let cert_identities: Vec<String> = cert_identities.iter().map(|identity| identity.to_string()).collect();
+        let client_auth = authorization.authenticate_cert(&cert_identities);
+        assert_eq!(expected.map(str::to_string), client_auth.and_then(|client_auth| client_auth.user));
+    }
+
+    #[test]
+    fn test_authenticate_cert_user_without_password() {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        assert!(authorization.authenticate(&opts(Some("dave@example.com"), Some(""), None), None).is_none());
+    }
+
+    #[test_case("orders.new", true; "allowed")]
+    #[test_case("orders.internal.audit", false; "denied inside allowed")]
+    #[test_case("payments.new", false; "not in allow list")]
+    fn test_can_publish(subject: &str, expected: bool) {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        let permissions = authorization.users[1].permissions.as_ref().unwrap();
+        assert_eq!(expected, permissions.can_publish(subject));
+    }
+
+    #[test_case("orders.new", true; "no allow list")]
+    #[test_case(">", true; "wildcard broader than deny")]
+    #[test_case("secret.key", false; "denied")]
+    #[test_case("secret.*", false; "denied wildcard")]
+    fn test_can_subscribe(subject: &str, expected: bool) {
+        let authorization: Authorization = toml::from_str(AUTHORIZATION).unwrap();
+        let permissions = authorization.users[1].permissions.as_ref().unwrap();
+        assert_eq!(expected, permissions.can_subscribe(subject));
+    }
+
+    #[test]
+    fn test_authenticate_not_required() {
+        let authorization = Authorization::default();
+        assert!(!authorization.is_required());
+        assert_eq!(Some(ClientAuth::default()), authorization.authenticate(&opts(None, None, None), None));
+    }
+}
@@ -1,17 +1,30 @@
-use crate::server::{ClientState, Server};
+use crate::account::{generate_service_reply, is_service_reply, ServiceReply, SERVICE_REPLY_TTL};
+use crate::server::{AccountMap, ClientState, Server, SubscriptionKey, GLOBAL_ACCOUNT};
+use bytes::Bytes;
 use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
 use std::collections::{HashMap, HashSet};
-use tokio::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::sync::RwLockWriteGuard;
+use crate::auth::ClientAuth;
+use crate::leafnode::Leafnode;
 use crate::parser::ClientConnectOpts;
+use crate::route::{unspecified_addr, Interest, Route, RouteCommand, RouteInfo, RoutedMessage};
+use crate::subject::{subject_matches, transform_subject};
+
+const NO_RESPONDERS_HEADERS: &[u8] = b"NATS/1.0 503\r\n\r\n";
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ClientCommand {
     Noop,
     Connect(ClientConnectOpts),
-    Pub { subject: String, msg: String },
-    Sub { subject: String, id: String },
-    Unsub { id: String },
+    Pub { subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes },
+    Sub { subject: String, queue_group: Option<String>, id: String },
+    Unsub { id: String, max_msgs: Option<u32> },
     Ping,
     Pong,
 }
@@ -20,12 +33,29 @@ pub enum ClientCommand {
 pub enum MainCommand {
     Noop,
     InitClient { client_id: u32, tx: Sender<MainCommand> },
-    Connect { client_id: u32, client_connect_opts: ClientConnectOpts },
+    // processed is notified once the client state is updated, so following commands see it
+    Connect { client_id: u32, client_connect_opts: ClientConnectOpts, client_auth: Box<ClientAuth>, processed: oneshot::Sender<bool> },
     Disconnect { client_id: u32 },
-    Subscribe { client_id: u32, subject: String, subscription_id: String },
-    Unsubscribe { client_id: u32, subscription_id: String },
-    Publish { subject: String, msg: String },
-    PublishedMessage { subject: String, msg: String, subscription_id: String },
+    Subscribe { client_id: u32, subject: String, queue_group: Option<String>, subscription_id: String },
+    Unsubscribe { client_id: u32, subscription_id: String, max_msgs: Option<u32> },
+    Publish { client_id: u32, subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes },
+    PublishedMessage { subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes, subscription_id: String },
+    AuthenticationExpired,
+    // sent to clients whenever the servers of the cluster change
+    AsyncInfo { connect_urls: Vec<String> },
+    // processed is notified whether the route is kept, there is only one route per server
+    RouteConnect { route_id: u32, info: Box<RouteInfo>, solicited: bool, tx: UnboundedSender<RouteCommand>, processed: oneshot::Sender<bool> },
+    RouteInfo { route_id: u32, info: Box<RouteInfo> },
+    RouteDisconnect { route_id: u32 },
+    RouteSubscribe { route_id: u32, interest: Interest },
+    RouteUnsubscribe { route_id: u32, interest: Interest },
+    RoutePublish { route_id: u32, message: RoutedMessage },
+    // processed is notified whether the leaf node is kept, there is only one leaf node per server or cluster
+    LeafnodeConnect { leafnode_id: u32, account: String, solicited: bool, info: Box<RouteInfo>, tx: UnboundedSender<RouteCommand>, processed: oneshot::Sender<bool> },
+    LeafnodeDisconnect { leafnode_id: u32 },
+    LeafnodeSubscribe { leafnode_id: u32, interest: Interest },
+    LeafnodeUnsubscribe { leafnode_id: u32, interest: Interest },
+    LeafnodePublish { leafnode_id: u32, message: RoutedMessage },
     ShutDown,
 }
 
@@ -37,132 +67,690 @@ impl Server {
         debug!("clients connected: {}", clients_tx.len());
     }
 
-    pub async fn process_connect(&self, client_id: u32, client_connect_opts: ClientConnectOpts) {
+    pub async fn process_connect(&self, client_id: u32, client_connect_opts: ClientConnectOpts, client_auth: Box<ClientAuth>, processed: oneshot::Sender<bool>) {
         let mut clients_tx = self.clients_tx.write().await;
+        // JWT accounts may limit the number of concurrent connections
+        if let (Some(account), Some(max_connections)) = (&client_auth.account, client_auth.max_connections) {
+            let connections = clients_tx.iter()
+                .filter(|(id, (_, client_state))| **id != client_id && client_state.connected && client_state.account.as_ref() == Some(account))
+                .count();
+            if connections >= max_connections as usize {
+                warn!("account {} reached its limit of {} connections", account, max_connections);
+                let _ = processed.send(false);
+                return;
+            }
+        }
         if let Some(pair) = clients_tx.get_mut(&client_id) {
+            debug!("client id {} connected with name {:?}, lang {:?}, version {:?}, protocol {}", client_id,
+                client_connect_opts.name, client_connect_opts.lang, client_connect_opts.version, client_connect_opts.protocol);
+            if let Some(expires) = client_auth.expires {
+                schedule_expiry(client_id, pair.0.clone(), expires);
+            }
             pair.1 = ClientState {
                 connected: true,
-                verbose: client_connect_opts.verbose
+                connect_opts: client_connect_opts,
+                permissions: client_auth.permissions,
+                account: client_auth.account,
             }
         } else {
             error!("unable to process connect");
         }
-        debug!("client id {} connected", client_id);
+        let _ = processed.send(true);
     }
 
-
     pub async fn process_disconnect(&self, client_id: u32) {
         let mut clients_tx = self.clients_tx.write().await;
-        clients_tx.remove(&client_id);
+        let account = clients_tx.remove(&client_id)
+            .map(|(_, client_state)| client_state.account().to_string())
+            .unwrap_or_else(|| GLOBAL_ACCOUNT.to_string());
 
         let mut lock = self.write_locks().await;
-        if let Some(subscription_ids) = lock.client_id_to_subscription_id.get(&client_id) {
+        let subscription_ids = lock.client_id_to_subscription_id.remove(&client_id).unwrap_or_default();
+        if let (Some(id_to_subject), Some(subject_to_id)) = (
+            lock.subscription_id_to_subject.get_mut(&account),
+            lock.subscription_subject_to_id.get_mut(&account),
+        ) {
             for subscription_id in subscription_ids {
-                if let Some(client_ids) = lock.subscription_id_to_client_id.get_mut(subscription_id) {
-                    client_ids.remove(&client_id);
-
-                    if !client_ids.is_empty() {
-                        continue;
-                    }
-
-                    // clean up the rest if no more client connected to this id
-                    if let Some(subjects) = lock.subscription_id_to_subject.get(subscription_id) {
-                        for subject in subjects {
-                            if let Some(subscription_ids) = lock.subscription_subject_to_id.get_mut(subject) {
-                                subscription_ids.remove(subscription_id);
-                            }
-                        }
-                        lock.subscription_id_to_subject.remove(subscription_id);
-                    }
+                let key = (client_id, subscription_id);
+                let queue_group = lock.subscription_queue_group.get(&key);
+                for subject in remove_subscription(id_to_subject, subject_to_id, &key) {
+                    remove_interest(&mut lock.client_interest, interest(&account, subject, queue_group.cloned()));
                 }
             }
         }
-        lock.client_id_to_subscription_id.remove(&client_id);
+        lock.subscription_queue_group.retain(|(id, _), _| *id != client_id);
+        lock.subscription_delivered.retain(|(id, _), _| *id != client_id);
+        lock.subscription_max_msgs.retain(|(id, _), _| *id != client_id);
 
         debug!("client id {} disconnected", client_id);
         debug!("clients connected: {}", clients_tx.len());
+        drop(lock);
+        drop(clients_tx);
+        self.propagate_interest().await;
     }
 
-    pub async fn process_subscribe(&self, client_id: u32, subject: String, subscription_id: String) {
-        let locks = self.write_locks().await;
-        insert_to_subscription_map(locks.subscription_subject_to_id, subject.clone(), subscription_id.clone());
-        insert_to_subscription_map(locks.subscription_id_to_subject, subscription_id.clone(), subject.clone());
-        insert_to_subscription_map(locks.subscription_id_to_client_id, subscription_id.clone(), client_id);
-        insert_to_subscription_map(locks.client_id_to_subscription_id, client_id, subscription_id.clone());
+    pub async fn process_subscribe(&self, client_id: u32, subject: String, queue_group: Option<String>, subscription_id: String) {
+        let account = self.client_account(client_id).await;
+        let mut locks = self.write_locks().await;
+        let key = (client_id, subscription_id.clone());
+        // a repeated SUB must not reset the count used by a pending auto unsubscribe
+        locks.subscription_delivered.entry(key.clone()).or_insert(0);
+        if let Some(queue_group) = &queue_group {
+            locks.subscription_queue_group.insert(key.clone(), queue_group.clone());
+        }
+        insert_to_subscription_map(locks.subscription_subject_to_id.entry(account.clone()).or_default(), subject.clone(), key.clone());
+        // a repeated SUB with the same sid and subject is the same subscription
+        if insert_to_subscription_map(locks.subscription_id_to_subject.entry(account.clone()).or_default(), key, subject.clone()) {
+            *locks.client_interest.entry(interest(&account, subject, queue_group)).or_insert(0) += 1;
+        }
+        insert_to_subscription_map(&mut locks.client_id_to_subscription_id, client_id, subscription_id);
+        drop(locks);
+        self.propagate_interest().await;
     }
 
-    pub async fn process_unsubscribe(&self, client_id: u32, subscription_id: String) {
+    pub async fn process_unsubscribe(&self, client_id: u32, subscription_id: String, max_msgs: Option<u32>) {
+        let account = self.client_account(client_id).await;
         let mut lock = self.write_locks().await;
+        let key = (client_id, subscription_id.clone());
+
+        // with max_msgs the subscription stays until it has received that many messages in total
+        if let Some(max_msgs) = max_msgs {
+            if let Some(delivered) = lock.subscription_delivered.get(&key) {
+                if max_msgs > *delivered {
+                    debug!("client id {} subscription {} auto unsubscribes after {} messages", client_id, subscription_id, max_msgs);
+                    lock.subscription_max_msgs.insert(key, max_msgs);
+                    return;
+                }
+            }
+        }
 
         if let Some(subscription_ids) = lock.client_id_to_subscription_id.get_mut(&client_id) {
             subscription_ids.remove(&subscription_id);
         }
-        if let Some(client_ids) = lock.subscription_id_to_client_id.get_mut(&subscription_id) {
-            client_ids.remove(&client_id);
-
-            if !client_ids.is_empty() {
-                return;
+        let queue_group = lock.subscription_queue_group.remove(&key);
+        lock.subscription_delivered.remove(&key);
+        lock.subscription_max_msgs.remove(&key);
+        if let (Some(id_to_subject), Some(subject_to_id)) = (
+            lock.subscription_id_to_subject.get_mut(&account),
+            lock.subscription_subject_to_id.get_mut(&account),
+        ) {
+            for subject in remove_subscription(id_to_subject, subject_to_id, &key) {
+                remove_interest(&mut lock.client_interest, interest(&account, subject, queue_group.clone()));
             }
         }
+        drop(lock);
+        self.propagate_interest().await;
+    }
 
-        // clean up the rest if no more client connected to this id
-        if let Some(subjects) = lock.subscription_id_to_subject.get(&subscription_id) {
-            for subject in subjects {
-                if let Some(subscription_ids) = lock.subscription_subject_to_id.get_mut(subject) {
-                    subscription_ids.remove(&subscription_id);
-                }
-            }
-           lock.subscription_id_to_subject.remove(&subscription_id);
-        }
+    async fn client_account(&self, client_id: u32) -> String {
+        let clients_tx = self.clients_tx.read().await;
+        clients_tx.get(&client_id)
+            .map(|(_, client_state)| client_state.account().to_string())
+            .unwrap_or_else(|| GLOBAL_ACCOUNT.to_string())
     }
 
     // ensure that locks are obtained in the same order
-    async fn write_locks(&self) -> MapWriteLocks {
+    async fn write_locks(&self) -> MapWriteLocks<'_> {
         let subscription_subject_to_id = self.subscription_subject_to_id.write().await;
         let subscription_id_to_subject = self.subscription_id_to_subject.write().await;
-        let subscription_id_to_client_id = self.subscription_id_to_client_id.write().await;
         let client_id_to_subscription_id = self.client_id_to_subscription_id.write().await;
+        let subscription_queue_group = self.subscription_queue_group.write().await;
+        let subscription_delivered = self.subscription_delivered.write().await;
+        let subscription_max_msgs = self.subscription_max_msgs.write().await;
+        let client_interest = self.client_interest.write().await;
         MapWriteLocks {
             subscription_subject_to_id,
             subscription_id_to_subject,
-            subscription_id_to_client_id,
-            client_id_to_subscription_id
+            client_id_to_subscription_id,
+            subscription_queue_group,
+            subscription_delivered,
+            subscription_max_msgs,
+            client_interest,
         }
     }
 
-    pub async fn process_publish(&self, subject: String, msg: String) {
+    pub async fn process_publish(&self, client_id: u32, subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes) {
         info!("process_publish");
+        let account = self.client_account(client_id).await;
+
+        if let Some(reply) = self.take_service_reply(&account, &subject).await {
+            debug!("routing service reply {} to account {} as {}", subject, reply.importer, reply.reply_to);
+            self.publish_to_account(&reply.importer, client_id, &reply.reply_to, None, headers, msg).await;
+            return;
+        }
+
+        let mut delivered = self.publish_to_account(&account, client_id, &subject, reply_to.clone(), headers.clone(), msg.clone()).await;
+
+        // streams exported by this account, replies are not routed across accounts
+        if let Some(stream_imports) = self.imports.streams.get(&account) {
+            for import in stream_imports {
+                if let Some(to) = transform_subject(&import.subject, &import.to, &subject) {
+                    debug!("stream {} imported by account {} as {}", subject, import.importer, to);
+                    delivered += self.publish_to_account(&import.importer, client_id, &to, None, headers.clone(), msg.clone()).await;
+                }
+            }
+        }
+
+        // services imported by this account, replies are mapped so they can be routed back
+        if let Some(service_imports) = self.imports.services.get(&account) {
+            for import in service_imports {
+                if let Some(to) = transform_subject(&import.to, &import.subject, &subject) {
+                    let service_reply = match &reply_to {
+                        Some(reply_to) => Some(self.map_service_reply(&import.exporter, &account, reply_to).await),
+                        None => None,
+                    };
+                    debug!("service {} forwarded to account {} as {}", subject, import.exporter, to);
+                    delivered += self.publish_to_account(&import.exporter, client_id, &to, service_reply, headers.clone(), msg.clone()).await;
+                }
+            }
+        }
+
+        if delivered == 0 {
+            warn!("unable to find subscription id for subject: {}", subject);
+            if let Some(reply_to) = reply_to {
+                self.send_no_responders(client_id, &account, &reply_to).await;
+            }
+        }
+    }
+
+    // replies to forwarded service requests go back to the requestor in the importing account
+    async fn take_service_reply(&self, account: &str, subject: &str) -> Option<ServiceReply> {
+        if !is_service_reply(subject) {
+            return None;
+        }
+        let mut service_replies = self.service_replies.write().await;
+        if service_replies.get(subject).is_some_and(|reply| reply.exporter == account) {
+            service_replies.remove(subject)
+        } else {
+            None
+        }
+    }
+
+    async fn map_service_reply(&self, exporter: &str, importer: &str, reply_to: &str) -> String {
+        let mut service_replies = self.service_replies.write().await;
+        service_replies.retain(|_, reply| reply.created.elapsed() < SERVICE_REPLY_TTL);
+        let service_reply = generate_service_reply();
+        service_replies.insert(service_reply.clone(), ServiceReply {
+            exporter: exporter.to_string(),
+            importer: importer.to_string(),
+            reply_to: reply_to.to_string(),
+            created: Instant::now(),
+        });
+        service_reply
+    }
+
+    // delivers to the subscribers of a single account and forwards to the routes and leaf nodes with
+    // interest, returns the number of subscriptions, routes and leaf nodes the message was sent to
+    async fn publish_to_account(&self, account: &str, client_id: u32, subject: &str, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes) -> usize {
+        let (delivered, queue_groups) = self.deliver_to_account(account, client_id, subject, reply_to.clone(), headers.clone(), msg.clone(), None).await;
+        let message = RoutedMessage {
+            account: account.to_string(),
+            subject: subject.to_string(),
+            reply_to,
+            headers,
+            msg,
+            queue_groups: vec![],
+        };
+        delivered + self.forward(client_id, message, None, &queue_groups).await
+    }
+
+    // delivers to the subscribers of a single account on this server, routed messages only reach the
+    // queue groups listed by the sending server, returns the number of subscriptions the message was
+    // sent to and the queue groups that received it
+    #[allow(clippy::too_many_arguments)]
+    async fn deliver_to_account(&self, account: &str, client_id: u32, subject: &str, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes, routed_queue_groups: Option<&[String]>) -> (usize, HashSet<String>) {
         let subscription_subject_to_id = self.subscription_subject_to_id.read().await;
-        let subscription_id_to_client_id = self.subscription_id_to_client_id.read().await;
         let clients_tx = self.clients_tx.read().await;
 
-        if let Some(subscription_ids) = subscription_subject_to_id.get(&subject) {
-            for subscription_id in subscription_ids {
-                if let Some(client_ids) = subscription_id_to_client_id.get(subscription_id) {
-                    for client_id in client_ids {
-                        if let Some((tx, _)) = clients_tx.get(client_id) {
-                            send_message(
-                                *client_id,
-                                tx.clone(),
-                                subscription_id.clone(),
-                                subject.clone(),
-                                msg.clone(),
-                            );
-                        } else {
-                            warn!("unable to find client tx for client id {}", client_id);
-                        }
+        // only subscriptions in the given account are visible
+        let no_subjects = HashMap::new();
+        let subject_to_id = subscription_subject_to_id.get(account).unwrap_or(&no_subjects);
+
+        let subscription_queue_group = self.subscription_queue_group.read().await;
+
+        // a subscription may be reachable through several matching patterns, only deliver once
+        let mut matched: HashSet<(u32, &String)> = HashSet::new();
+        let mut recipients: Vec<(u32, &String)> = vec![];
+        // queue group members are collected first so only one member per group receives the message,
+        // whichever pattern they subscribed with
+        let mut queue_groups: HashMap<&String, Vec<(u32, &String)>> = HashMap::new();
+
+        let matching_subscriptions = subject_to_id.iter()
+            .filter(|(pattern, _)| subject_matches(pattern, subject));
+
+        let echo = clients_tx.get(&client_id).map(|(_, client_state)| client_state.connect_opts.echo).unwrap_or(true);
+
+        for (_, subscription_keys) in matching_subscriptions {
+            for key in subscription_keys {
+                let (subscriber_id, subscription_id) = (key.0, &key.1);
+                if subscriber_id == client_id && !echo {
+                    continue;
+                }
+                if !matched.insert((subscriber_id, subscription_id)) {
+                    continue;
+                }
+                match subscription_queue_group.get(key) {
+                    Some(queue_group) if routed_queue_groups.is_some_and(|queue_groups| !queue_groups.contains(queue_group)) => {}
+                    Some(queue_group) => {
+                        queue_groups.entry(queue_group)
+                            .or_default()
+                            .push((subscriber_id, subscription_id));
                     }
-                } else {
-                    warn!("unable to find client ids for subscription id {}", subscription_id);
+                    None => recipients.push((subscriber_id, subscription_id)),
                 }
             }
-        } else {
-            warn!("unable to find subscription id for subject: {}", subject);
         }
+
+        // wildcard subscriptions may still cover subjects the subscriber is denied, such members
+        // must not be picked for their queue group
+        let can_subscribe = |client_id: &u32| {
+            let permissions = clients_tx.get(client_id).and_then(|(_, client_state)| client_state.permissions.as_ref());
+            if permissions.is_some_and(|permissions| !permissions.can_subscribe(subject)) {
+                debug!("client id {} is not allowed to receive subject {}", client_id, subject);
+                return false;
+            }
+            true
+        };
+        recipients.retain(|(client_id, _)| can_subscribe(client_id));
+
+        let mut served_queue_groups = HashSet::new();
+        for (queue_group, members) in &mut queue_groups {
+            members.retain(|(client_id, _)| can_subscribe(client_id));
+            if let Some(member) = members.choose(&mut rand::thread_rng()) {
+                recipients.push(*member);
+                served_queue_groups.insert(queue_group.to_string());
+            }
+        }
+
+        let mut subscription_delivered = self.subscription_delivered.write().await;
+        let subscription_max_msgs = self.subscription_max_msgs.read().await;
+        let mut expired: Vec<(u32, String)> = vec![];
+        let mut delivered = 0;
+
+        for (client_id, subscription_id) in recipients {
+            let Some((tx, client_state)) = clients_tx.get(&client_id) else {
+                warn!("unable to find client tx for client id {}", client_id);
+                continue;
+            };
+            // headers are dropped for clients that did not declare header support
+            let headers = if client_state.connect_opts.headers { headers.clone() } else { None };
+            send_message(
+                client_id,
+                tx.clone(),
+                subscription_id.clone(),
+                subject.to_string(),
+                reply_to.clone(),
+                headers,
+                msg.clone(),
+            );
+            delivered += 1;
+
+            let key = (client_id, subscription_id.clone());
+            let delivered = subscription_delivered.entry(key.clone()).or_insert(0);
+            *delivered += 1;
+            if subscription_max_msgs.get(&key).is_some_and(|max_msgs| *delivered >= *max_msgs) {
+                expired.push(key);
+            }
+        }
+
+        // release the read locks before removing the expired subscriptions
+        drop(subscription_max_msgs);
+        drop(subscription_delivered);
+        drop(subscription_queue_group);
+        drop(clients_tx);
+        drop(subscription_subject_to_id);
+
+        for (client_id, subscription_id) in expired {
+            debug!("client id {} subscription {} reached max messages", client_id, subscription_id);
+            self.process_unsubscribe(client_id, subscription_id, None).await;
+        }
+        (delivered, served_queue_groups)
+    }
+
+    // routes and leaf nodes only receive messages they have interest in, each queue group that was
+    // not served locally is handed to a single one of them, messages from a route are only passed on
+    // to the leaf nodes as every server of the cluster has a route of its own
+    async fn forward(&self, origin_id: u32, message: RoutedMessage, routed_queue_groups: Option<&[String]>, served_queue_groups: &HashSet<String>) -> usize {
+        let routes = self.routes.read().await;
+        let route_interest = self.route_interest.read().await;
+        let leafnodes = self.leafnodes.read().await;
+        let from_route = routes.values().any(|route| route.route_id == origin_id);
+        let no_interest = HashSet::new();
+        let targets: Vec<(u32, &UnboundedSender<RouteCommand>, &HashSet<Interest>)> = routes.values()
+            .filter(|_| !from_route)
+            .map(|route| (route.route_id, &route.tx, route_interest.get(&route.route_id).unwrap_or(&no_interest)))
+            .chain(leafnodes.values()
+                .filter(|leafnode| leafnode.leafnode_id != origin_id && leafnode.account == message.account)
+                .map(|leafnode| (leafnode.leafnode_id, &leafnode.tx, &leafnode.interest)))
+            .collect();
+        // the server that forwarded a service request holds the reply mapping, so replies go everywhere
+        let service_reply = is_service_reply(&message.subject);
+
+        let mut forwards: HashMap<u32, Vec<String>> = HashMap::new();
+        let mut queue_group_targets: HashMap<&String, Vec<u32>> = HashMap::new();
+        for (id, _, interest) in &targets {
+            if service_reply {
+                forwards.entry(*id).or_default();
+            }
+            let matching_interest = interest.iter()
+                .filter(|interest| interest.account == message.account && subject_matches(&interest.subject, &message.subject));
+            for interest in matching_interest {
+                match &interest.queue_group {
+                    None => {
+                        forwards.entry(*id).or_default();
+                    }
+                    Some(queue_group) if routed_queue_groups.is_some_and(|queue_groups| !queue_groups.contains(queue_group)) => {}
+                    Some(queue_group) if !served_queue_groups.contains(queue_group) => {
+                        queue_group_targets.entry(queue_group).or_default().push(*id);
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        for (queue_group, ids) in queue_group_targets {
+            if let Some(id) = ids.choose(&mut rand::thread_rng()) {
+                forwards.entry(*id).or_default().push(queue_group.clone());
+            }
+        }
+
+        let mut forwarded = 0;
+        for (id, tx, _) in targets {
+            let Some(queue_groups) = forwards.remove(&id) else {
+                continue;
+            };
+            debug!("forwarding {} to {}", message.subject, id);
+            if let Err(e) = tx.send(RouteCommand::Msg(RoutedMessage { queue_groups, ..message.clone() })) {
+                error!("error sending message to {}: {}", id, e);
+                continue;
+            }
+            forwarded += 1;
+        }
+        forwarded
+    }
+
+    // requests without any subscriber get an immediate 503 status on the reply subject
+    async fn send_no_responders(&self, client_id: u32, account: &str, reply_to: &str) {
+        let subscription_subject_to_id = self.subscription_subject_to_id.read().await;
+        let clients_tx = self.clients_tx.read().await;
+
+        let Some((tx, client_state)) = clients_tx.get(&client_id) else {
+            return;
+        };
+        if !(client_state.connect_opts.no_responders && client_state.connect_opts.headers) {
+            return;
+        }
+        let Some(subject_to_id) = subscription_subject_to_id.get(account) else {
+            return;
+        };
+        let reply_subscription_ids = subject_to_id.iter()
+            .filter(|(pattern, _)| subject_matches(pattern, reply_to))
+            .flat_map(|(_, subscription_keys)| subscription_keys)
+            .filter(|(id, _)| *id == client_id)
+            .map(|(_, subscription_id)| subscription_id);
+        for subscription_id in reply_subscription_ids {
+            send_message(
+                client_id,
+                tx.clone(),
+                subscription_id.clone(),
+                reply_to.to_string(),
+                None,
+                Some(Bytes::from_static(NO_RESPONDERS_HEADERS)),
+                Bytes::new(),
+            );
+        }
+    }
+
+    // the subscriptions of local clients and leaf nodes, recomputed on every change so routes only
+    // hear about the first and last subscriber of each subject
+    async fn propagate_interest(&self) {
+        if self.config.cluster.is_some() {
+            let mut interest: HashSet<Interest> = self.client_interest.read().await.keys().cloned().collect();
+            interest.extend(self.leafnodes.read().await.values().flat_map(|leafnode| leafnode.interest.iter().cloned()));
+            let mut advertised_interest = self.advertised_interest.write().await;
+            let routes = self.routes.read().await;
+            for route in routes.values() {
+                for added in interest.difference(&advertised_interest) {
+                    let _ = route.tx.send(RouteCommand::Subscribe(added.clone()));
+                }
+                for removed in advertised_interest.difference(&interest) {
+                    let _ = route.tx.send(RouteCommand::Unsubscribe(removed.clone()));
+                }
+            }
+            *advertised_interest = interest;
+        }
+        self.propagate_leafnode_interest().await;
+    }
+
+    // each leaf node hears about the subscriptions in its account of the local clients, the routes
+    // and the other leaf nodes
+    async fn propagate_leafnode_interest(&self) {
+        let mut leafnodes = self.leafnodes.write().await;
+        if leafnodes.is_empty() {
+            return;
+        }
+        let mut interest: HashSet<Interest> = self.client_interest.read().await.keys().cloned().collect();
+        interest.extend(self.route_interest.read().await.values().flatten().cloned());
+        let leafnode_interest: Vec<(u32, Interest)> = leafnodes.values()
+            .flat_map(|leafnode| leafnode.interest.iter().map(move |interest| (leafnode.leafnode_id, interest.clone())))
+            .collect();
+        for leafnode in leafnodes.values_mut() {
+            let leafnode_id = leafnode.leafnode_id;
+            let interest: HashSet<Interest> = interest.iter()
+                .chain(leafnode_interest.iter().filter(|(id, _)| *id != leafnode_id).map(|(_, interest)| interest))
+                .filter(|interest| interest.account == leafnode.account)
+                .cloned()
+                .collect();
+            for added in interest.difference(&leafnode.advertised_interest) {
+                let _ = leafnode.tx.send(RouteCommand::Subscribe(added.clone()));
+            }
+            for removed in leafnode.advertised_interest.difference(&interest) {
+                let _ = leafnode.tx.send(RouteCommand::Unsubscribe(removed.clone()));
+            }
+            leafnode.advertised_interest = interest;
+        }
+    }
+
+    // a server may be connected through both its own route and the route of this server, both
+    // servers keep the one opened by the server with the lower id
+    pub async fn process_route_connect(self: &Arc<Self>, route_id: u32, info: Box<RouteInfo>, solicited: bool, tx: UnboundedSender<RouteCommand>, processed: oneshot::Sender<bool>) {
+        let server_id = info.server_id.clone();
+        if server_id == self.server_id {
+            let _ = processed.send(false);
+            return;
+        }
+        let mut routes = self.routes.write().await;
+        let replaced = match routes.get(&server_id) {
+            Some(_) if solicited != (self.server_id < server_id) => {
+                let _ = processed.send(false);
+                return;
+            }
+            Some(_) => routes.remove(&server_id),
+            None => None,
+        };
+        if let Some(replaced) = &replaced {
+            debug!("route {} to server {} replaced by route {}", replaced.route_id, server_id, route_id);
+            self.route_interest.write().await.remove(&replaced.route_id);
+        }
+
+        for interest in self.advertised_interest.read().await.iter() {
+            let _ = tx.send(RouteCommand::Subscribe(interest.clone()));
+        }
+        let discovered_routes = info.routes.clone();
+        routes.insert(server_id, Route { route_id, info: *info, tx });
+        debug!("routes connected: {}", routes.len());
+        drop(routes);
+        let _ = processed.send(true);
+        if replaced.is_some() {
+            self.propagate_leafnode_interest().await;
+            return;
+        }
+
+        // every server learns about the new one, and this server about the servers it knows
+        let route_info = self.route_info().await;
+        for route in self.routes.read().await.values() {
+            let _ = route.tx.send(RouteCommand::Info(route_info.clone()));
+        }
+        self.connect_routes(discovered_routes, true).await;
+        self.send_connect_urls().await;
+    }
+
+    pub async fn process_route_info(self: &Arc<Self>, route_id: u32, info: Box<RouteInfo>) {
+        let discovered_routes = info.routes.clone();
+        if let Some(route) = self.routes.write().await.values_mut().find(|route| route.route_id == route_id) {
+            route.info = *info;
+        }
+        self.connect_routes(discovered_routes, true).await;
+    }
+
+    pub async fn process_route_disconnect(&self, route_id: u32) {
+        let mut routes = self.routes.write().await;
+        let connected = routes.len();
+        routes.retain(|_, route| route.route_id != route_id);
+        self.route_interest.write().await.remove(&route_id);
+        debug!("route {} disconnected", route_id);
+        debug!("routes connected: {}", routes.len());
+        if routes.len() != connected {
+            drop(routes);
+            self.send_connect_urls().await;
+            self.propagate_leafnode_interest().await;
+        }
+    }
+
+    // the client addresses of all servers of the cluster, starting with this one unless it listens
+    // on all interfaces without `client_advertise`, clients could not connect to that address
+    pub async fn connect_urls(&self) -> Vec<String> {
+        let routes = self.routes.read().await;
+        self.client_url().into_iter()
+            .filter(|client_url| unspecified_addr(client_url).is_none())
+            .chain(routes.values().map(|route| route.info.client_url.clone()))
+            .filter(|client_url| !client_url.is_empty())
+            .collect()
+    }
+
+    // clients that support async INFO learn about the servers they can fail over to
+    async fn send_connect_urls(&self) {
+        let connect_urls = self.connect_urls().await;
+        let clients_tx = self.clients_tx.read().await;
+        let clients = clients_tx.iter()
+            .filter(|(_, (_, client_state))| client_state.connected && client_state.connect_opts.protocol >= 1);
+        for (client_id, (tx, _)) in clients {
+            let client_id = *client_id;
+            let tx = tx.clone();
+            let connect_urls = connect_urls.clone();
+            tokio::spawn(async move {
+                if let Err(e) = tx.send(MainCommand::AsyncInfo { connect_urls }).await {
+                    error!("error sending info to client {}: {}", client_id, e);
+                }
+            });
+        }
+    }
+
+    pub async fn process_route_subscribe(&self, route_id: u32, interest: Interest) {
+        // a replaced route may still have commands in flight
+        if !self.routes.read().await.values().any(|route| route.route_id == route_id) {
+            return;
+        }
+        self.route_interest.write().await.entry(route_id).or_default().insert(interest);
+        self.propagate_leafnode_interest().await;
+    }
+
+    pub async fn process_route_unsubscribe(&self, route_id: u32, interest: Interest) {
+        if let Some(route_interest) = self.route_interest.write().await.get_mut(&route_id) {
+            route_interest.remove(&interest);
+        }
+        self.propagate_leafnode_interest().await;
+    }
+
+    pub async fn process_route_publish(&self, route_id: u32, message: RoutedMessage) {
+        self.publish_routed(route_id, message).await;
+    }
+
+    // leaf node accounts are mapped on both sides, so the hub and the leaf node may share
+    // differently named accounts
+    pub async fn process_leafnode_connect(&self, leafnode_id: u32, account: String, solicited: bool, info: Box<RouteInfo>, tx: UnboundedSender<RouteCommand>, processed: oneshot::Sender<bool>) {
+        if info.server_id == self.server_id {
+            let _ = processed.send(false);
+            return;
+        }
+        let server_id = info.server_id.clone();
+        let cluster: HashSet<String> = info.cluster.iter().cloned().chain([server_id.clone()]).collect();
+        let mut leafnodes = self.leafnodes.write().await;
+        // messages sent over one link would come back over the other, when both servers solicit each
+        // other they keep the link opened by the server with the lower id
+        let duplicate = leafnodes.values()
+            .find(|leafnode| !leafnode.cluster.is_disjoint(&cluster))
+            .map(|leafnode| (leafnode.leafnode_id, leafnode.server_id == server_id));
+        match duplicate {
+            Some((replaced_id, true)) if solicited == (self.server_id < server_id) => {
+                debug!("leaf node {} to server {} replaced by leaf node {}", replaced_id, server_id, leafnode_id);
+                leafnodes.remove(&replaced_id);
+            }
+            Some((existing_id, _)) => {
+                debug!("server {} is already connected through leaf node {}", server_id, existing_id);
+                let _ = processed.send(false);
+                return;
+            }
+            None => {}
+        }
+        debug!("leaf node {} to server {} bound to account {}", leafnode_id, info.server_name, account);
+        leafnodes.insert(leafnode_id, Leafnode {
+            leafnode_id,
+            server_id,
+            cluster,
+            account,
+            tx,
+            interest: HashSet::new(),
+            advertised_interest: HashSet::new(),
+        });
+        debug!("leaf nodes connected: {}", leafnodes.len());
+        drop(leafnodes);
+        let _ = processed.send(true);
+        self.propagate_interest().await;
+    }
+
+    pub async fn process_leafnode_disconnect(&self, leafnode_id: u32) {
+        if self.leafnodes.write().await.remove(&leafnode_id).is_some() {
+            debug!("leaf node {} disconnected", leafnode_id);
+            self.propagate_interest().await;
+        }
+    }
+
+    pub async fn process_leafnode_subscribe(&self, leafnode_id: u32, interest: Interest) {
+        if let Some(leafnode) = self.leafnodes.write().await.get_mut(&leafnode_id) {
+            leafnode.interest.insert(interest);
+        }
+        self.propagate_interest().await;
+    }
+
+    pub async fn process_leafnode_unsubscribe(&self, leafnode_id: u32, interest: Interest) {
+        if let Some(leafnode) = self.leafnodes.write().await.get_mut(&leafnode_id) {
+            leafnode.interest.remove(&interest);
+        }
+        self.propagate_interest().await;
+    }
+
+    pub async fn process_leafnode_publish(&self, leafnode_id: u32, message: RoutedMessage) {
+        self.publish_routed(leafnode_id, message).await;
+    }
+
+    // messages of other servers are delivered locally and passed on to the leaf nodes, and to the
+    // routes if they came from a leaf node, imports are applied by the server the publisher is connected to
+    async fn publish_routed(&self, origin_id: u32, message: RoutedMessage) {
+        if let Some(reply) = self.take_service_reply(&message.account, &message.subject).await {
+            debug!("routing service reply {} to account {} as {}", message.subject, reply.importer, reply.reply_to);
+            self.publish_to_account(&reply.importer, origin_id, &reply.reply_to, None, message.headers, message.msg).await;
+            return;
+        }
+        let queue_groups = message.queue_groups.clone();
+        let (_, served_queue_groups) = self.deliver_to_account(&message.account, origin_id, &message.subject, message.reply_to.clone(), message.headers.clone(), message.msg.clone(), Some(&queue_groups)).await;
+        self.forward(origin_id, message, Some(&queue_groups), &served_queue_groups).await;
     }
 
     pub async fn process_shutdown(&self) {
         info!("process shutdown");
+        // route and leaf node handlers stop once their channel is dropped
+        self.routes.write().await.clear();
+        self.leafnodes.write().await.clear();
         if let Ok(clients_tx) = self.clients_tx.try_read() {
             for (client_id, (tx, _)) in clients_tx.iter() {
                 if let Err(e) = tx.try_send(MainCommand::ShutDown) {
@@ -174,33 +762,658 @@ impl Server {
 }
 
 struct MapWriteLocks<'a> {
-    subscription_subject_to_id: RwLockWriteGuard<'a, HashMap<String, HashSet<String>>>,
-    subscription_id_to_subject: RwLockWriteGuard<'a, HashMap<String, HashSet<String>>>,
-    subscription_id_to_client_id: RwLockWriteGuard<'a, HashMap<String, HashSet<u32>>>,
+    subscription_subject_to_id: RwLockWriteGuard<'a, AccountMap<String, SubscriptionKey>>,
+    subscription_id_to_subject: RwLockWriteGuard<'a, AccountMap<SubscriptionKey, String>>,
     client_id_to_subscription_id: RwLockWriteGuard<'a, HashMap<u32, HashSet<String>>>,
+    subscription_queue_group: RwLockWriteGuard<'a, HashMap<(u32, String), String>>,
+    subscription_delivered: RwLockWriteGuard<'a, HashMap<(u32, String), u32>>,
+    subscription_max_msgs: RwLockWriteGuard<'a, HashMap<(u32, String), u32>>,
+    client_interest: RwLockWriteGuard<'a, HashMap<Interest, usize>>,
 }
 
-fn insert_to_subscription_map<K, V>(mut map: RwLockWriteGuard<HashMap<K, HashSet<V>>>, key: K, value: V)
+// removes a subscription from the subject index of its account, subjects without subscriptions are
+// dropped, returns the subjects of the subscription
+fn remove_subscription(id_to_subject: &mut HashMap<SubscriptionKey, HashSet<String>>, subject_to_id: &mut HashMap<String, HashSet<SubscriptionKey>>, key: &SubscriptionKey) -> HashSet<String> {
+    let subjects = id_to_subject.remove(key).unwrap_or_default();
+    for subject in &subjects {
+        if let Some(subscription_keys) = subject_to_id.get_mut(subject) {
+            subscription_keys.remove(key);
+            if subscription_keys.is_empty() {
+                subject_to_id.remove(subject);
+            }
+        }
+    }
+    subjects
+}
+
+fn interest(account: &str, subject: String, queue_group: Option<String>) -> Interest {
+    Interest { account: account.to_string(), subject, queue_group }
+}
+
+fn remove_interest(client_interest: &mut HashMap<Interest, usize>, interest: Interest) {
+    if let Some(count) = client_interest.get_mut(&interest) {
+        *count -= 1;
+        if *count == 0 {
+            client_interest.remove(&interest);
+        }
+    }
+}
+
+// returns whether the value was not in the set of the key yet
+fn insert_to_subscription_map<K, V>(map: &mut HashMap<K, HashSet<V>>, key: K, value: V) -> bool
 where
     K: Eq + std::hash::Hash,
     V: Eq + std::hash::Hash,
 {
-    match map.get_mut(&key) {
-        Some(values) => {
-            values.insert(value);
-        }
-        None => {
-            let values = HashSet::from([value]);
-            map.insert(key, values);
-        }
-    }
+    map.entry(key).or_default().insert(value)
 }
 
-fn send_message(client_id: u32, client_tx: Sender<MainCommand>, subscription_id: String, subject: String, msg: String) {
+fn send_message(client_id: u32, client_tx: Sender<MainCommand>, subscription_id: String, subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes) {
     info!("publishing message to client id {} for subject {}", client_id, subject);
     tokio::spawn(async move {
-        if let Err(e) = client_tx.send(MainCommand::PublishedMessage { subject, msg, subscription_id }).await {
+        if let Err(e) = client_tx.send(MainCommand::PublishedMessage { subject, reply_to, headers, msg, subscription_id }).await {
             error!("error sending message to client {}: {}", client_id, e);
         }
     });
 }
+
+// disconnects the client once its credentials expire, `expires` is in unix seconds
+fn schedule_expiry(client_id: u32, client_tx: Sender<MainCommand>, expires: u64) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let remaining = Duration::from_secs(expires.saturating_sub(now));
+    debug!("client id {} credentials expire in {:?}", client_id, remaining);
+    tokio::spawn(async move {
+        tokio::time::sleep(remaining).await;
+        // the client may already be gone
+        let _ = client_tx.send(MainCommand::AuthenticationExpired).await;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Config, Permissions, SubjectPermission};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::{channel, Receiver, UnboundedReceiver};
+
+    const ACCOUNTS: &str = r#"
+        [accounts.team_a]
+        exports = [
+            { stream = "events.>" },
+            { service = "help", accounts = ["team_b"] },
+        ]
+
+        [accounts.team_b]
+        imports = [
+            { stream = { account = "team_a", subject = "events.orders.>" }, to = "a.orders.>" },
+            { service = { account = "team_a", subject = "help" }, to = "a.help" },
+        ]
+    "#;
+
+    fn server() -> Server {
+        server_with("")
+    }
+
+    fn server_with(config: &str) -> Server {
+        let config: Config = toml::from_str(&format!("listeners = [{{ address = \"127.0.0.1:4222\" }}]\n{}", config)).unwrap();
+        Server::new(config).0
+    }
+
+    async fn connect(server: &Server, client_id: u32, account: Option<&str>) -> Receiver<MainCommand> {
+        let client_auth = ClientAuth { account: account.map(str::to_string), ..Default::default() };
+        connect_with(server, client_id, client_auth, ClientConnectOpts::default()).await
+    }
+
+    async fn connect_with(server: &Server, client_id: u32, client_auth: ClientAuth, connect_opts: ClientConnectOpts) -> Receiver<MainCommand> {
+        let (tx, rx) = channel(100);
+        server.process_init_client(client_id, tx).await;
+        let (processed, _) = oneshot::channel();
+        server.process_connect(client_id, connect_opts, Box::new(client_auth), processed).await;
+        rx
+    }
+
+    async fn publish(server: &Server, client_id: u32, subject: &str) {
+        server.process_publish(client_id, subject.to_string(), None, None, Bytes::from("hello")).await;
+        // messages are handed to the clients from spawned tasks
+        tokio::task::yield_now().await;
+    }
+
+    fn received(rx: &mut Receiver<MainCommand>) -> Vec<(String, String)> {
+        let mut received = vec![];
+        while let Ok(command) = rx.try_recv() {
+            if let MainCommand::PublishedMessage { subject, subscription_id, .. } = command {
+                received.push((subject, subscription_id));
+            }
+        }
+        received
+    }
+
+    // subject and reply subject of the messages received by a client
+    fn received_replies(rx: &mut Receiver<MainCommand>) -> Vec<(String, Option<String>)> {
+        let mut received = vec![];
+        while let Ok(command) = rx.try_recv() {
+            if let MainCommand::PublishedMessage { subject, reply_to, .. } = command {
+                received.push((subject, reply_to));
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_publish_same_sid_of_different_clients() {
+        let server = server();
+        let mut foo = connect(&server, 1, None).await;
+        let mut bar = connect(&server, 2, None).await;
+        let _publisher = connect(&server, 3, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(2, "bar".to_string(), None, "1".to_string()).await;
+
+        publish(&server, 3, "foo").await;
+        assert_eq!(vec![("foo".to_string(), "1".to_string())], received(&mut foo));
+        assert!(received(&mut bar).is_empty());
+
+        // unsubscribing one client keeps the subscription of the other
+        server.process_unsubscribe(1, "1".to_string(), None).await;
+        publish(&server, 3, "bar").await;
+        assert_eq!(vec![("bar".to_string(), "1".to_string())], received(&mut bar));
+        assert!(received(&mut foo).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_no_echo() {
+        let server = server();
+        let connect_opts = ClientConnectOpts { echo: false, ..Default::default() };
+        let mut publisher = connect_with(&server, 1, ClientAuth::default(), connect_opts).await;
+        let mut subscriber = connect(&server, 2, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(2, "foo".to_string(), None, "1".to_string()).await;
+
+        publish(&server, 1, "foo").await;
+        assert!(received(&mut publisher).is_empty());
+        assert_eq!(vec![("foo".to_string(), "1".to_string())], received(&mut subscriber));
+
+        // messages of other clients still reach the client
+        publish(&server, 2, "foo").await;
+        assert_eq!(vec![("foo".to_string(), "1".to_string())], received(&mut publisher));
+    }
+
+    #[tokio::test]
+    async fn test_publish_does_not_cross_accounts() {
+        let server = server();
+        let mut team_a = connect(&server, 1, Some("team_a")).await;
+        let mut team_b = connect(&server, 2, Some("team_b")).await;
+        let mut global = connect(&server, 3, None).await;
+        let _publisher = connect(&server, 4, Some("team_a")).await;
+        for client_id in 1..=3 {
+            server.process_subscribe(client_id, ">".to_string(), None, "1".to_string()).await;
+        }
+
+        publish(&server, 4, "orders.new").await;
+        assert_eq!(vec![("orders.new".to_string(), "1".to_string())], received(&mut team_a));
+        assert!(received(&mut team_b).is_empty());
+        assert!(received(&mut global).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_queue_group() {
+        let server = server();
+        let mut star = connect(&server, 1, None).await;
+        let mut full_wildcard = connect(&server, 2, None).await;
+        let mut plain = connect(&server, 3, None).await;
+        let permissions = Permissions {
+            subscribe: SubjectPermission { allow: None, deny: vec!["foo.bar".to_string()] },
+            ..Default::default()
+        };
+        let client_auth = ClientAuth { permissions: Some(permissions), ..Default::default() };
+        let mut denied = connect_with(&server, 4, client_auth, ClientConnectOpts::default()).await;
+        let mut other_group = connect(&server, 5, None).await;
+        let _publisher = connect(&server, 6, None).await;
+        // members of one group subscribed with different patterns
+        server.process_subscribe(1, "foo.*".to_string(), Some("workers".to_string()), "1".to_string()).await;
+        server.process_subscribe(2, "foo.>".to_string(), Some("workers".to_string()), "1".to_string()).await;
+        server.process_subscribe(3, "foo.*".to_string(), None, "1".to_string()).await;
+        // the denied member is never picked so the other member receives every message
+        server.process_subscribe(4, "foo.>".to_string(), Some("audit".to_string()), "1".to_string()).await;
+        server.process_subscribe(5, "foo.*".to_string(), Some("audit".to_string()), "1".to_string()).await;
+
+        for _ in 0..20 {
+            publish(&server, 6, "foo.bar").await;
+        }
+        assert_eq!(20, received(&mut star).len() + received(&mut full_wildcard).len());
+        assert_eq!(20, received(&mut plain).len());
+        assert!(received(&mut denied).is_empty());
+        assert_eq!(20, received(&mut other_group).len());
+    }
+
+    #[tokio::test]
+    async fn test_publish_stream_import() {
+        let server = server_with(ACCOUNTS);
+        let mut importer = connect(&server, 1, Some("team_b")).await;
+        let _exporter = connect(&server, 2, Some("team_a")).await;
+        server.process_subscribe(1, ">".to_string(), None, "1".to_string()).await;
+
+        publish(&server, 2, "events.orders.new").await;
+        assert_eq!(vec![("a.orders.new".to_string(), "1".to_string())], received(&mut importer));
+        // only the imported subjects cross the accounts
+        publish(&server, 2, "events.users.new").await;
+        assert!(received(&mut importer).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_service_import() {
+        let server = server_with(ACCOUNTS);
+        let mut requestor = connect(&server, 1, Some("team_b")).await;
+        let mut exporter = connect(&server, 2, Some("team_a")).await;
+        let _other = connect(&server, 3, Some("team_b")).await;
+        server.process_subscribe(1, "inbox".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(2, "help".to_string(), None, "1".to_string()).await;
+
+        server.process_publish(1, "a.help".to_string(), Some("inbox".to_string()), None, Bytes::from("hello")).await;
+        tokio::task::yield_now().await;
+        let requests = received_replies(&mut exporter);
+        assert_eq!(1, requests.len());
+        let (subject, service_reply) = &requests[0];
+        assert_eq!("help", subject);
+        let service_reply = service_reply.clone().unwrap();
+        assert!(is_service_reply(&service_reply));
+
+        // the reply is only routed when published by the exporting account
+        publish(&server, 3, &service_reply).await;
+        assert!(received(&mut requestor).is_empty());
+
+        publish(&server, 2, &service_reply).await;
+        assert_eq!(vec![("inbox".to_string(), "1".to_string())], received(&mut requestor));
+        // only the first reply is routed
+        publish(&server, 2, &service_reply).await;
+        assert!(received(&mut requestor).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_max_msgs() {
+        let server = server();
+        let mut subscriber = connect(&server, 1, None).await;
+        let _publisher = connect(&server, 2, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_unsubscribe(1, "1".to_string(), Some(2)).await;
+
+        for _ in 0..4 {
+            publish(&server, 2, "foo").await;
+        }
+        assert_eq!(2, received(&mut subscriber).len());
+        assert!(server.client_interest.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_max_msgs_already_delivered() {
+        let server = server();
+        let mut subscriber = connect(&server, 1, None).await;
+        let _publisher = connect(&server, 2, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        publish(&server, 2, "foo").await;
+        publish(&server, 2, "foo").await;
+        // a repeated SUB keeps the count of delivered messages
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        assert_eq!(2, received(&mut subscriber).len());
+
+        server.process_unsubscribe(1, "1".to_string(), Some(2)).await;
+        assert!(server.client_interest.read().await.is_empty());
+        publish(&server, 2, "foo").await;
+        assert!(received(&mut subscriber).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_interest() {
+        let server = server();
+        let _a = connect(&server, 1, None).await;
+        let _b = connect(&server, 2, Some("team_a")).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(1, "foo".to_string(), None, "2".to_string()).await;
+        server.process_subscribe(2, "bar".to_string(), Some("workers".to_string()), "1".to_string()).await;
+        let expected = HashMap::from([
+            (interest(GLOBAL_ACCOUNT, "foo".to_string(), None), 2),
+            (interest("team_a", "bar".to_string(), Some("workers".to_string())), 1),
+        ]);
+        assert_eq!(expected, *server.client_interest.read().await);
+
+        server.process_unsubscribe(1, "1".to_string(), None).await;
+        server.process_disconnect(2).await;
+        let expected = HashMap::from([(interest(GLOBAL_ACCOUNT, "foo".to_string(), None), 1)]);
+        assert_eq!(expected, *server.client_interest.read().await);
+
+        server.process_disconnect(1).await;
+        assert!(server.client_interest.read().await.is_empty());
+    }
+
+    const CLUSTER: &str = r#"
+        [cluster]
+        listener = "127.0.0.1:6222"
+    "#;
+
+    fn route_message(subject: &str, queue_groups: &[&str]) -> RoutedMessage {
+        RoutedMessage {
+            account: GLOBAL_ACCOUNT.to_string(),
+            subject: subject.to_string(),
+            reply_to: None,
+            headers: None,
+            msg: Bytes::from("hello"),
+            queue_groups: queue_groups.iter().map(|queue_group| queue_group.to_string()).collect(),
+        }
+    }
+
+    async fn connect_route(server: &Arc<Server>, route_id: u32, server_id: &str) -> UnboundedReceiver<RouteCommand> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, connect_processed) = oneshot::channel();
+        let info = RouteInfo { server_id: server_id.to_string(), ..Default::default() };
+        server.process_route_connect(route_id, Box::new(info), true, tx, processed).await;
+        assert!(connect_processed.await.unwrap());
+        // the INFO sent to every route once the cluster changes
+        route_commands(&mut rx);
+        rx
+    }
+
+    fn route_commands(rx: &mut UnboundedReceiver<RouteCommand>) -> Vec<RouteCommand> {
+        let mut commands = vec![];
+        while let Ok(command) = rx.try_recv() {
+            commands.push(command);
+        }
+        commands
+    }
+
+    // subject and queue groups of the messages forwarded to a route or leaf node
+    fn forwarded(rx: &mut UnboundedReceiver<RouteCommand>) -> Vec<(String, Vec<String>)> {
+        route_commands(rx).into_iter()
+            .filter_map(|command| match command {
+                RouteCommand::Msg(message) => Some((message.subject, message.queue_groups)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // the route solicited from the discovered server, kept open so it stays solicited
+    async fn accept_route(listener: &TcpListener) -> BufReader<TcpStream> {
+        let (socket, _) = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.unwrap().unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut line = String::new();
+        socket.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        socket
+    }
+
+    #[tokio::test]
+    async fn test_route_discovery() {
+        let server = Arc::new(server_with(CLUSTER));
+        let listeners = [TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap()];
+        let [first, second] = listeners.each_ref().map(|listener| listener.local_addr().unwrap().to_string());
+
+        // the routes of a new server are solicited, this server and connected servers are skipped
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, _) = oneshot::channel();
+        let info = RouteInfo {
+            server_id: "S2".to_string(),
+            route_url: "127.0.0.1:6223".to_string(),
+            routes: vec![first.clone(), "127.0.0.1:6222".to_string(), "127.0.0.1:6223".to_string()],
+            ..Default::default()
+        };
+        server.process_route_connect(100, Box::new(info), false, tx, processed).await;
+        let _first_route = accept_route(&listeners[0]).await;
+        assert_eq!(HashSet::from([first.clone()]), *server.solicited_routes.read().await);
+
+        // as are the routes of servers joining later
+        let info = RouteInfo { server_id: "S2".to_string(), routes: vec![first.clone(), second.clone()], ..Default::default() };
+        server.process_route_info(100, Box::new(info)).await;
+        let _second_route = accept_route(&listeners[1]).await;
+        assert_eq!(HashSet::from([first, second]), *server.solicited_routes.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_route_connect_urls() {
+        let server = Arc::new(server_with(CLUSTER));
+        let connect_opts = ClientConnectOpts { protocol: 1, ..Default::default() };
+        let mut dynamic = connect_with(&server, 1, ClientAuth::default(), connect_opts).await;
+        let mut original = connect(&server, 2, None).await;
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, _) = oneshot::channel();
+        let info = RouteInfo { server_id: "S2".to_string(), client_url: "127.0.0.1:4223".to_string(), ..Default::default() };
+        server.process_route_connect(100, Box::new(info), false, tx, processed).await;
+        tokio::task::yield_now().await;
+        assert!(matches!(dynamic.try_recv(), Ok(MainCommand::AsyncInfo { connect_urls }) if connect_urls == vec!["127.0.0.1:4222", "127.0.0.1:4223"]));
+        // clients without protocol 1 do not support async INFO
+        assert!(original.try_recv().is_err());
+
+        server.process_route_disconnect(100).await;
+        tokio::task::yield_now().await;
+        assert!(matches!(dynamic.try_recv(), Ok(MainCommand::AsyncInfo { connect_urls }) if connect_urls == vec!["127.0.0.1:4222"]));
+    }
+
+    #[tokio::test]
+    async fn test_connect_urls_unspecified_listener() {
+        let config: Config = toml::from_str(r#"listeners = [{ address = "0.0.0.0:4222" }]"#).unwrap();
+        let server = Server::new(config).0;
+        assert!(server.connect_urls().await.is_empty());
+
+        let config: Config = toml::from_str(r#"
+            client_advertise = "server1:4222"
+            listeners = [{ address = "0.0.0.0:4222" }]
+        "#).unwrap();
+        let server = Server::new(config).0;
+        assert_eq!(vec!["server1:4222"], server.connect_urls().await);
+    }
+
+    #[tokio::test]
+    async fn test_route_interest_first_and_last_subscriber() {
+        let server = Arc::new(server_with(CLUSTER));
+        let mut route = connect_route(&server, 100, "S2").await;
+        let _a = connect(&server, 1, None).await;
+        let _b = connect(&server, 2, None).await;
+        let foo = interest(GLOBAL_ACCOUNT, "foo".to_string(), None);
+
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        assert_eq!(vec![RouteCommand::Subscribe(foo.clone())], route_commands(&mut route));
+        server.process_subscribe(2, "foo".to_string(), None, "1".to_string()).await;
+        assert!(route_commands(&mut route).is_empty());
+
+        server.process_unsubscribe(1, "1".to_string(), None).await;
+        assert!(route_commands(&mut route).is_empty());
+        server.process_unsubscribe(2, "1".to_string(), None).await;
+        assert_eq!(vec![RouteCommand::Unsubscribe(foo.clone())], route_commands(&mut route));
+
+        // a new route receives the interest already advertised
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, _) = oneshot::channel();
+        server.process_route_connect(101, Box::new(RouteInfo { server_id: "S3".to_string(), ..Default::default() }), true, tx, processed).await;
+        assert_eq!(Some(RouteCommand::Subscribe(foo)), route_commands(&mut rx).into_iter().next());
+    }
+
+    #[tokio::test]
+    async fn test_route_forward_interest() {
+        let server = Arc::new(server_with(CLUSTER));
+        let mut foo_route = connect_route(&server, 100, "S2").await;
+        let mut bar_route = connect_route(&server, 101, "S3").await;
+        let mut subscriber = connect(&server, 1, None).await;
+        let _publisher = connect(&server, 2, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_route_subscribe(100, interest(GLOBAL_ACCOUNT, "foo".to_string(), None)).await;
+        server.process_route_subscribe(101, interest(GLOBAL_ACCOUNT, "bar".to_string(), None)).await;
+        server.process_route_subscribe(101, interest("team_a", "foo".to_string(), None)).await;
+
+        publish(&server, 2, "foo").await;
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut foo_route));
+        assert!(forwarded(&mut bar_route).is_empty());
+        received(&mut subscriber);
+
+        // every server of the cluster has a route of its own, so routed messages are not passed on
+        server.process_route_subscribe(101, interest(GLOBAL_ACCOUNT, "foo".to_string(), None)).await;
+        server.process_route_publish(100, route_message("foo", &[])).await;
+        tokio::task::yield_now().await;
+        assert_eq!(vec![("foo".to_string(), "1".to_string())], received(&mut subscriber));
+        assert!(forwarded(&mut foo_route).is_empty());
+        assert!(forwarded(&mut bar_route).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_route_forward_queue_group() {
+        let server = Arc::new(server_with(CLUSTER));
+        let mut routes = [connect_route(&server, 100, "S2").await, connect_route(&server, 101, "S3").await];
+        let mut member = connect(&server, 1, None).await;
+        let _publisher = connect(&server, 2, None).await;
+        for route_id in [100, 101] {
+            server.process_route_subscribe(route_id, interest(GLOBAL_ACCOUNT, "foo".to_string(), Some("workers".to_string()))).await;
+        }
+
+        // a queue group served by this server is not forwarded
+        server.process_subscribe(1, "foo".to_string(), Some("workers".to_string()), "1".to_string()).await;
+        publish(&server, 2, "foo").await;
+        assert_eq!(1, received(&mut member).len());
+        assert!(routes.iter_mut().all(|route| forwarded(route).is_empty()));
+
+        // otherwise a single route serves it
+        server.process_unsubscribe(1, "1".to_string(), None).await;
+        publish(&server, 2, "foo").await;
+        let forwarded: Vec<(String, Vec<String>)> = routes.iter_mut().flat_map(forwarded).collect();
+        assert_eq!(vec![("foo".to_string(), vec!["workers".to_string()])], forwarded);
+    }
+
+    #[tokio::test]
+    async fn test_route_publish_queue_groups() {
+        let server = Arc::new(server_with(CLUSTER));
+        let _route = connect_route(&server, 100, "S2").await;
+        let mut plain = connect(&server, 1, None).await;
+        let mut workers = connect(&server, 2, None).await;
+        let mut others = connect(&server, 3, None).await;
+        server.process_subscribe(1, "foo".to_string(), None, "1".to_string()).await;
+        server.process_subscribe(2, "foo".to_string(), Some("workers".to_string()), "1".to_string()).await;
+        server.process_subscribe(3, "foo".to_string(), Some("others".to_string()), "1".to_string()).await;
+
+        // the sending server already delivered to the groups it did not list
+        server.process_route_publish(100, route_message("foo", &["workers"])).await;
+        tokio::task::yield_now().await;
+        assert_eq!(1, received(&mut plain).len());
+        assert_eq!(1, received(&mut workers).len());
+        assert!(received(&mut others).is_empty());
+
+        server.process_route_publish(100, route_message("foo", &[])).await;
+        tokio::task::yield_now().await;
+        assert_eq!(1, received(&mut plain).len());
+        assert!(received(&mut workers).is_empty());
+        assert!(received(&mut others).is_empty());
+    }
+
+    async fn connect_leafnode(server: &Server, leafnode_id: u32, server_id: &str, cluster: &[&str], solicited: bool) -> bool {
+        let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, connect_processed) = oneshot::channel();
+        let info = RouteInfo {
+            server_id: server_id.to_string(),
+            cluster: cluster.iter().map(|server_id| server_id.to_string()).collect(),
+            ..Default::default()
+        };
+        server.process_leafnode_connect(leafnode_id, GLOBAL_ACCOUNT.to_string(), solicited, Box::new(info), tx, processed).await;
+        connect_processed.await.unwrap()
+    }
+
+    async fn leafnode(server: &Server, leafnode_id: u32, server_id: &str, account: &str) -> UnboundedReceiver<RouteCommand> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (processed, connect_processed) = oneshot::channel();
+        let info = RouteInfo { server_id: server_id.to_string(), ..Default::default() };
+        server.process_leafnode_connect(leafnode_id, account.to_string(), true, Box::new(info), tx, processed).await;
+        assert!(connect_processed.await.unwrap());
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_leafnode_interest() {
+        let server = Arc::new(server_with(CLUSTER));
+        let mut route = connect_route(&server, 100, "S2").await;
+        let mut origin = leafnode(&server, 200, "S3", GLOBAL_ACCOUNT).await;
+        let mut other = leafnode(&server, 201, "S4", GLOBAL_ACCOUNT).await;
+        let mut team_a = leafnode(&server, 202, "S5", "team_a").await;
+        let _client = connect(&server, 1, Some("team_a")).await;
+
+        // interest of a leaf node reaches the routes and the other leaf nodes of its account
+        let foo = interest(GLOBAL_ACCOUNT, "foo".to_string(), None);
+        server.process_leafnode_subscribe(200, foo.clone()).await;
+        assert_eq!(vec![RouteCommand::Subscribe(foo.clone())], route_commands(&mut route));
+        assert_eq!(vec![RouteCommand::Subscribe(foo.clone())], route_commands(&mut other));
+        assert!(route_commands(&mut origin).is_empty());
+        assert!(route_commands(&mut team_a).is_empty());
+
+        let bar = interest(GLOBAL_ACCOUNT, "bar".to_string(), None);
+        server.process_route_subscribe(100, bar.clone()).await;
+        assert_eq!(vec![RouteCommand::Subscribe(bar.clone())], route_commands(&mut origin));
+        assert_eq!(vec![RouteCommand::Subscribe(bar)], route_commands(&mut other));
+        assert!(route_commands(&mut route).is_empty());
+        assert!(route_commands(&mut team_a).is_empty());
+
+        let baz = interest("team_a", "baz".to_string(), None);
+        server.process_subscribe(1, "baz".to_string(), None, "1".to_string()).await;
+        assert_eq!(vec![RouteCommand::Subscribe(baz.clone())], route_commands(&mut team_a));
+        assert_eq!(vec![RouteCommand::Subscribe(baz)], route_commands(&mut route));
+        assert!(route_commands(&mut origin).is_empty());
+        assert!(route_commands(&mut other).is_empty());
+
+        server.process_leafnode_disconnect(200).await;
+        assert_eq!(vec![RouteCommand::Unsubscribe(foo.clone())], route_commands(&mut route));
+        assert_eq!(vec![RouteCommand::Unsubscribe(foo)], route_commands(&mut other));
+    }
+
+    #[tokio::test]
+    async fn test_leafnode_forward() {
+        let server = Arc::new(server_with(CLUSTER));
+        let mut route = connect_route(&server, 100, "S2").await;
+        let mut origin = leafnode(&server, 200, "S3", GLOBAL_ACCOUNT).await;
+        let mut other = leafnode(&server, 201, "S4", GLOBAL_ACCOUNT).await;
+        let mut team_a = leafnode(&server, 202, "S5", "team_a").await;
+        let _publisher = connect(&server, 1, Some("team_a")).await;
+        server.process_route_subscribe(100, interest(GLOBAL_ACCOUNT, "foo".to_string(), None)).await;
+        for leafnode_id in [200, 201] {
+            server.process_leafnode_subscribe(leafnode_id, interest(GLOBAL_ACCOUNT, "foo".to_string(), None)).await;
+        }
+        server.process_leafnode_subscribe(202, interest("team_a", "foo".to_string(), None)).await;
+
+        // messages of a leaf node go to the routes and the other leaf nodes, never back to it
+        server.process_leafnode_publish(200, route_message("foo", &[])).await;
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut route));
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut other));
+        assert!(forwarded(&mut origin).is_empty());
+        assert!(forwarded(&mut team_a).is_empty());
+
+        // messages of a route only go to the leaf nodes
+        server.process_route_publish(100, route_message("foo", &[])).await;
+        assert!(forwarded(&mut route).is_empty());
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut origin));
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut other));
+        assert!(forwarded(&mut team_a).is_empty());
+
+        // only the leaf node bound to the account of the publisher receives its messages
+        publish(&server, 1, "foo").await;
+        assert_eq!(vec![("foo".to_string(), vec![])], forwarded(&mut team_a));
+        assert!(forwarded(&mut route).is_empty());
+        assert!(forwarded(&mut origin).is_empty());
+        assert!(forwarded(&mut other).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_leafnode_connect_same_server() {
+        let server = server();
+        // the link opened by the server with the lower id is kept
+        let kept_solicited = server.server_id.as_str() < "S2";
+        assert!(connect_leafnode(&server, 1, "S2", &[], !kept_solicited).await);
+        assert!(connect_leafnode(&server, 2, "S2", &[], kept_solicited).await);
+        assert!(!connect_leafnode(&server, 3, "S2", &[], !kept_solicited).await);
+        assert_eq!(vec![2], server.leafnodes.read().await.keys().copied().collect::<Vec<_>>());
+        assert!(!connect_leafnode(&server, 4, &server.server_id, &[], true).await);
+    }
+
+    #[tokio::test]
+    async fn test_leafnode_connect_same_cluster() {
+        let server = server();
+        assert!(connect_leafnode(&server, 1, "S2", &["S3"], true).await);
+        assert!(!connect_leafnode(&server, 2, "S3", &["S2"], true).await);
+        assert!(!connect_leafnode(&server, 3, "S4", &["S2"], true).await);
+        assert!(connect_leafnode(&server, 4, "S5", &[], true).await);
+        assert_eq!(2, server.leafnodes.read().await.len());
+    }
+}
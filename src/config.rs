@@ -1,11 +1,217 @@
+use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
 use serde::Deserialize;
+use crate::parser::{DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    pub listeners: Vec<Listener>,
+    // advertised in INFO, defaults to the generated server id
+    pub server_name: Option<String>,
+    // address handed to clients in `connect_urls`, defaults to the address of the first listener,
+    // which the other servers of the cluster replace with the address of the route when it is on all interfaces
+    pub client_advertise: Option<String>,
+    #[serde(default = "default_max_payload")]
+    pub max_payload: usize,
+    #[serde(default = "default_max_control_line")]
+    pub max_control_line: usize,
+    // seconds between server initiated PINGs
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    // unanswered PINGs before the connection is considered stale
+    #[serde(default = "default_ping_max")]
+    pub ping_max: u32,
+    // shared with the blocking tasks that verify bcrypt passwords and read account JWTs
+    pub authorization: Option<Arc<Authorization>>,
+    pub websocket: Option<WebSocket>,
+    pub cluster: Option<Cluster>,
+    pub leafnodes: Option<Leafnodes>,
+    // path of an additional unix domain socket listener, access is controlled by the file permissions
+    pub unix_socket: Option<String>,
+    // account name to the subjects it shares with other accounts
+    #[serde(default)]
+    pub accounts: HashMap<String, Account>,
+}
+
+// client listener with its own settings, e.g. loopback without auth next to a public address with TLS
+#[derive(Debug, Deserialize)]
+pub struct Listener {
+    pub address: String,
+    pub tls: Option<Tls>,
+    // clients of this listener skip authentication and get full access to the global account
+    #[serde(default)]
+    pub no_auth: bool,
+}
+
+// client connections upgrade to TLS right after INFO, `ca_file` with `verify` requires
+// client certificates, `verify_and_map` also uses the certificate subject as the user
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    pub cert_file: String,
+    pub key_file: String,
+    pub ca_file: Option<String>,
+    #[serde(default)]
+    pub verify: bool,
+    #[serde(default)]
+    pub verify_and_map: bool,
+    // plaintext clients are still accepted, TLS is only advertised as available
+    #[serde(default)]
+    pub allow_non_tls: bool,
+}
+
+// second listener for browser clients, the protocol is carried in WebSocket frames
+#[derive(Debug, Deserialize)]
+pub struct WebSocket {
     pub listener: String,
 }
 
+// other servers of the cluster connect to `listener`, `routes` are the servers this server connects to,
+// further servers are discovered from the routes
+#[derive(Debug, Deserialize)]
+pub struct Cluster {
+    pub listener: String,
+    // address other servers connect to when they discover this server, defaults to `listener` with
+    // an address on all interfaces replaced by the address the route connects from
+    pub advertise: Option<String>,
+    #[serde(default)]
+    pub routes: Vec<String>,
+    // required from the other servers and presented to them, so every server of the cluster uses the same
+    pub authorization: Option<LinkAuthorization>,
+}
+
+// credentials of route and leaf node connections, sent with CONNECT before INFO
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkAuthorization {
+    pub user: String,
+    pub password: String,
+}
+
+// leaf nodes connect to `listener` and share its `account`, `remotes` are the hubs this server
+// connects to as a leaf node
+#[derive(Debug, Default, Deserialize)]
+pub struct Leafnodes {
+    pub listener: Option<String>,
+    // account of the servers connected as leaf nodes, defaults to the global account
+    pub account: Option<String>,
+    // required from the servers connecting as leaf nodes, the password may be a bcrypt hash
+    pub authorization: Option<LinkAuthorization>,
+    #[serde(default)]
+    pub remotes: Vec<LeafnodeRemote>,
+}
+
+// hub this server connects to, only the subscriptions and messages of `account` are bridged
+#[derive(Debug, Deserialize)]
+pub struct LeafnodeRemote {
+    pub url: String,
+    // local account bridged to the hub, defaults to the global account
+    pub account: Option<String>,
+    // presented to the hub when it requires them
+    pub credentials: Option<LinkAuthorization>,
+}
+
+// either a single user/password, a token, a list of users or nkeys, or user JWTs
+// issued by accounts signed by one of the trusted operator keys
+#[derive(Debug, Default, Deserialize)]
+pub struct Authorization {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub nkeys: Vec<NkeyUser>,
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    pub resolver: Option<Resolver>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub user: String,
+    // users without a password can only authenticate with a client certificate
+    pub password: Option<String>,
+    pub permissions: Option<Permissions>,
+    // users without an account share the global account
+    pub account: Option<String>,
+}
+
+// authenticated by signing the INFO nonce with the private key of the public nkey
+#[derive(Debug, Deserialize)]
+pub struct NkeyUser {
+    pub nkey: String,
+    pub permissions: Option<Permissions>,
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Account {
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+}
+
+// either a `stream` or a `service` subject, any account may import it unless `accounts` is set
+#[derive(Debug, Deserialize)]
+pub struct Export {
+    pub stream: Option<String>,
+    pub service: Option<String>,
+    #[serde(default)]
+    pub accounts: Vec<String>,
+}
+
+// either a `stream` or a `service` of another account, optionally available under the local subject `to`
+#[derive(Debug, Deserialize)]
+pub struct Import {
+    pub stream: Option<ImportSource>,
+    pub service: Option<ImportSource>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportSource {
+    pub account: String,
+    pub subject: String,
+}
+
+// account JWTs are read from `<dir>/<account public key>.jwt`
+#[derive(Debug, Deserialize)]
+pub struct Resolver {
+    pub dir: String,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq)]
+pub struct Permissions {
+    #[serde(default)]
+    pub publish: SubjectPermission,
+    #[serde(default)]
+    pub subscribe: SubjectPermission,
+}
+
+// subjects may use wildcards, without an allow list every subject not denied is allowed
+#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq)]
+pub struct SubjectPermission {
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+fn default_max_payload() -> usize {
+    DEFAULT_MAX_PAYLOAD
+}
+
+fn default_max_control_line() -> usize {
+    DEFAULT_MAX_CONTROL_LINE
+}
+
+fn default_ping_interval() -> u64 {
+    120
+}
+
+fn default_ping_max() -> u32 {
+    2
+}
+
 pub fn parse_config(conf: &str) -> Config {
     let contents = fs::read_to_string(conf).expect("Unable to read config file");
     let config: Config = toml::from_str(&contents).expect("Unable to parse config file");
@@ -1,26 +1,209 @@
-use io::ErrorKind::NotConnected;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering::SeqCst;
+use bytes::Bytes;
+use crate::auth::{generate_nonce, ClientAuth};
 use crate::commands::MainCommand::{Connect, Disconnect, InitClient};
 use crate::commands::{ClientCommand, MainCommand};
-use crate::parser::{ClientConnectOpts, ClientRequest};
-use crate::server::Server;
+use crate::config::{Listener, Permissions, Tls};
+use crate::parser::{ClientConnectOpts, ClientRequest, ParseError};
+use crate::server::{Server, ServerInfo};
+use crate::subject::{is_valid_publish_subject, is_valid_subscription_subject};
+use crate::tls::{cert_identities, TLS_HANDSHAKE_RECORD};
 use log::{debug, error, info, warn};
-use serde_json::json;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, Error};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// error messages follow the NATS protocol so clients can recognise them
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Unknown Protocol Operation")]
+    UnknownProtocolOperation,
+    #[error("Maximum Payload Violation")]
+    MaxPayloadViolation,
+    #[error("Maximum Control Line Exceeded")]
+    MaxControlLineExceeded,
+    #[error("Authorization Violation")]
+    AuthorizationViolation,
+    #[error("User Authentication Expired")]
+    AuthenticationExpired,
+    #[error("maximum account active connections exceeded")]
+    MaxAccountConnectionsExceeded,
+    #[error("Secure Connection - TLS Required")]
+    SecureConnectionRequired,
+    #[error("Stale Connection")]
+    StaleConnection,
+    #[error("Invalid Subject")]
+    InvalidSubject,
+    #[error("Invalid Publish Subject")]
+    InvalidPublishSubject,
+    #[error("Permissions Violation for Publish to {0}")]
+    PublishPermissionViolation(String),
+    #[error("Permissions Violation for Subscription to {0}")]
+    SubscribePermissionViolation(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+// PINGs sent on every ping interval that were not answered with PONG yet
+pub struct Keepalive {
+    pings_outstanding: u32,
+    ping_max: u32,
+}
+
+impl Keepalive {
+    pub fn new(ping_max: u32) -> Self {
+        Self { pings_outstanding: 0, ping_max }
+    }
+
+    // called on every ping interval, returns false instead of counting another PING once the connection is stale
+    pub fn ping(&mut self) -> bool {
+        if self.pings_outstanding >= self.ping_max {
+            return false;
+        }
+        self.pings_outstanding += 1;
+        true
+    }
+
+    pub fn pong(&mut self) {
+        self.pings_outstanding = 0;
+    }
+
+    pub fn pings_outstanding(&self) -> u32 {
+        self.pings_outstanding
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+}
+
+// per connection state, mostly needed to authenticate the client in CONNECT
+struct ConnectionContext {
+    // INFO sent on connect, sent again with updated connect urls
+    info: ServerInfo,
+    nonce: Option<String>,
+    // names of the verified client certificate, only set when they are mapped to users
+    cert_identities: Option<Vec<String>>,
+    no_auth: bool,
+}
+
+impl ClientError {
+    // fatal errors close the connection after the error is sent
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self,
+            ClientError::InvalidSubject |
+            ClientError::InvalidPublishSubject |
+            ClientError::PublishPermissionViolation(_) |
+            ClientError::SubscribePermissionViolation(_))
+    }
+}
+
+impl From<ParseError> for ClientError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::MaxPayloadExceeded => ClientError::MaxPayloadViolation,
+            ParseError::MaxControlLineExceeded => ClientError::MaxControlLineExceeded,
+            _ => ClientError::UnknownProtocolOperation,
+        }
+    }
+}
 
 impl Server {
-    pub async fn handle(&self, mut socket: TcpStream) {
-        let mut req_buffer = [0; 4096];
-        let mut client_request = ClientRequest::new();
+    // clients of the client listener at `listener` in the config, upgraded to TLS after INFO when configured
+    pub async fn handle_tcp(&self, mut socket: TcpStream, listener: usize) {
+        let addr = match (socket.local_addr(), socket.peer_addr()) {
+            (Ok(local), Ok(peer)) => ClientAddr { local, peer },
+            (Err(e), _) | (_, Err(e)) => {
+                error!("error handling connection: {}", e);
+                return;
+            }
+        };
+        let listener_config = &self.config.listeners[listener];
+        let (client_id, nonce) = self.new_client(listener_config.no_auth);
+        let info = match self.handle_new_connection(client_id, nonce.clone(), Some(addr), Some(listener_config), &mut socket).await {
+            Ok(info) => info,
+            Err(e) => {
+                error!("error handling connection: {}", e);
+                return;
+            }
+        };
 
-        if let Err(e) = self.handle_new_connection(&mut socket).await {
-            error!("error handling connection: {}", e);
-            return;
+        let context = ConnectionContext { info, nonce, cert_identities: None, no_auth: listener_config.no_auth };
+        match (&listener_config.tls, &self.tls_acceptors[listener]) {
+            (Some(tls), Some(tls_acceptor)) => self.handle_tls(client_id, tls, tls_acceptor, socket, context).await,
+            _ => self.serve(client_id, socket, context).await,
         }
+    }
+
+    // clients over any other transport, e.g. WebSocket, which carries the same protocol without TLS upgrade,
+    // unix socket clients have no address
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: S, addr: Option<ClientAddr>) {
+        let (client_id, nonce) = self.new_client(false);
+        let info = match self.handle_new_connection(client_id, nonce.clone(), addr, None, &mut socket).await {
+            Ok(info) => info,
+            Err(e) => {
+                error!("error handling connection: {}", e);
+                return;
+            }
+        };
+        self.serve(client_id, socket, ConnectionContext { info, nonce, cert_identities: None, no_auth: false }).await
+    }
 
+    fn new_client(&self, no_auth: bool) -> (u32, Option<String>) {
         let client_id = self.client_id.fetch_add(1, SeqCst);
+        // nkey clients prove their identity by signing this nonce in CONNECT
+        let nonce = (!no_auth && self.nonce_required()).then(generate_nonce);
+        (client_id, nonce)
+    }
+
+    // NATS clients start the TLS handshake after receiving the plaintext INFO
+    async fn handle_tls(&self, client_id: u32, tls: &Tls, tls_acceptor: &TlsAcceptor, mut socket: TcpStream, mut context: ConnectionContext) {
+        let mut first_byte = [0; 1];
+        match timeout(TLS_HANDSHAKE_TIMEOUT, socket.peek(&mut first_byte)).await {
+            Ok(Ok(n)) if n > 0 => {}
+            _ => {
+                debug!("client {} closed or timed out before the TLS handshake", client_id);
+                return;
+            }
+        }
+        if first_byte[0] != TLS_HANDSHAKE_RECORD {
+            if tls.allow_non_tls {
+                return self.serve(client_id, socket, context).await;
+            }
+            self.handle_error(client_id, &mut socket, &ClientError::SecureConnectionRequired).await;
+            return;
+        }
+
+        let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                error!("TLS handshake failed for client {}: {}", client_id, e);
+                return;
+            }
+            Err(_) => {
+                error!("TLS handshake timed out for client {}", client_id);
+                return;
+            }
+        };
+        context.cert_identities = tls.verify_and_map.then(|| stream.get_ref().1.peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(cert_identities)
+            .unwrap_or_default());
+        self.serve(client_id, stream, context).await
+    }
+
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, client_id: u32, mut socket: S, mut context: ConnectionContext) {
+        let mut req_buffer = [0; 4096];
+        let mut client_request = ClientRequest::new(self.config.max_payload, self.config.max_control_line);
 
         let (tx, mut rx) = tokio::sync::mpsc::channel::<MainCommand>(100);
 
@@ -29,9 +212,26 @@ impl Server {
             return;
         }
 
+        let ping_period = Duration::from_secs(self.config.ping_interval.max(1));
+        let mut ping_interval = tokio::time::interval_at(Instant::now() + ping_period, ping_period);
+        let mut keepalive = Keepalive::new(self.config.ping_max);
+
         // loop here so we can stream the input (large input)
         loop {
             tokio::select! {
+                // keepalive, clients that stop answering PING are considered stale
+                _ = ping_interval.tick() => {
+                    if !keepalive.ping() {
+                        info!("client {} has {} outstanding pings", client_id, keepalive.pings_outstanding());
+                        self.handle_error(client_id, &mut socket, &ClientError::StaleConnection).await;
+                        break;
+                    }
+                    if let Err(e) = socket.write_all(b"PING\r\n").await {
+                        error!("error writing to socket: {}", e);
+                        break;
+                    }
+                }
+
                 // read input
                 socket_result = socket.read(&mut req_buffer) => {
                     match socket_result {
@@ -43,25 +243,41 @@ impl Server {
                             info!("n = {}", n);
                             let mut start = 0;
 
+                            let mut fatal = false;
                             while start < n {
                                 let (parsed, bytes_read) =  client_request.parse(&req_buffer[start..n]);
-                                match parsed {
+                                let cmd_result = match parsed {
                                     Ok(cmd) => {
                                         info!("command={:?}", cmd);
-                                        self.handle_commands(cmd, &mut socket, client_id).await;
+                                        if cmd == ClientCommand::Pong {
+                                            keepalive.pong();
+                                        }
+                                        self.handle_commands(cmd, &mut socket, client_id, &context).await
                                     }
 
                                     Err(e) => {
                                         error!("error parsing command: {}", e);
-                                        let _ = socket.write_all(b"-ERR\n").await;
+                                        Err(e.into())
+                                    }
+                                };
+                                if let Err(e) = cmd_result {
+                                    self.handle_error(client_id, &mut socket, &e).await;
+                                    if e.is_fatal() {
+                                        fatal = true;
+                                        break;
                                     }
                                 }
                                 start += bytes_read + 1;
                             }
+                            if fatal {
+                                info!("closing connection for client {}", client_id);
+                                break;
+                            }
                             info!("ok done, waiting for next");
                         }
                         Err(e) => {
                             error!("error: {}", e);
+                            break;
                         }
                     }
 
@@ -70,13 +286,24 @@ impl Server {
                 // read from main command channel
                 Some(cmd) = rx.recv() => {
                     match cmd {
-                       MainCommand::PublishedMessage{subject, msg, subscription_id} => {
+                       MainCommand::PublishedMessage{subject, reply_to, headers, msg, subscription_id} => {
                             let mut buf: Vec<u8> = vec![];
-                            let msg_bytes = msg.as_bytes();
-                            let response = format!("MSG {} {} {}\r\n", subject, subscription_id, msg_bytes.len());
+                            let op = if headers.is_some() { "HMSG" } else { "MSG" };
+                            let mut response = format!("{} {} {}", op, subject, subscription_id);
+                            if let Some(reply_to) = reply_to {
+                                response.push(' ');
+                                response.push_str(&reply_to);
+                            }
+                            match &headers {
+                                Some(headers) => response.push_str(&format!(" {} {}\r\n", headers.len(), headers.len() + msg.len())),
+                                None => response.push_str(&format!(" {}\r\n", msg.len())),
+                            }
 
                             buf.extend_from_slice(response.as_bytes());
-                            buf.extend_from_slice(msg_bytes);
+                            if let Some(headers) = headers {
+                                buf.extend_from_slice(&headers);
+                            }
+                            buf.extend_from_slice(&msg);
                             buf.push(b'\r');
                             buf.push(b'\n');
 
@@ -85,13 +312,24 @@ impl Server {
                             }
                             debug!("publish message for subject {}", subject);
                         },
+                        MainCommand::AsyncInfo { connect_urls } => {
+                            context.info.connect_urls = connect_urls;
+                            if let Err(e) = write_info(&mut socket, &context.info).await {
+                                error!("error writing to socket: {}", e);
+                            }
+                        },
+                        MainCommand::AuthenticationExpired => {
+                            info!("credentials of client {} expired", client_id);
+                            self.handle_error(client_id, &mut socket, &ClientError::AuthenticationExpired).await;
+                            break;
+                        },
                         MainCommand::ShutDown => {
                             info!("shutting down client {}", client_id);
                             // skip sending disconnect command
                             return;
                         },
                         _ => {
-                            warn!("received command on the client side, should be PublishedMessage or ShutDown only: {:?}", cmd);
+                            warn!("received command on the client side, should be PublishedMessage, AsyncInfo, AuthenticationExpired or ShutDown only: {:?}", cmd);
                         }
                     };
                 }
@@ -103,44 +341,68 @@ impl Server {
         }
     }
 
-    async fn handle_new_connection(&self, socket: &mut TcpStream) -> Result<(), Error> {
-        let local_addr = socket.local_addr().expect("Failed to get local address");
-        let peer_addr = socket.peer_addr().expect("Failed to get peer address");
-
-        let info = json!({
-            "hostname": local_addr.ip().to_string(),
-            "port": local_addr.port(),
-            "client_ip": peer_addr.ip().to_string(),
-            "max_payload": 1048576,
-        });
-
-        let response = format!("INFO {}\n", info);
-
-        socket.write_all(response.as_bytes()).await?;
-        Ok(())
+    async fn handle_new_connection(&self, client_id: u32, nonce: Option<String>, addr: Option<ClientAddr>, listener: Option<&Listener>, socket: &mut (impl AsyncWrite + Unpin)) -> Result<ServerInfo, io::Error> {
+        let info = self.server_info(client_id, addr, nonce, listener).await;
+        write_info(socket, &info).await?;
+        Ok(info)
     }
 
-    async fn handle_connect(&self, client_id: u32, socket: &mut TcpStream, client_connect_opts: ClientConnectOpts) -> Result<(), Error> {
+    async fn handle_connect(&self, client_id: u32, context: &ConnectionContext, socket: &mut (impl AsyncWrite + Unpin), client_connect_opts: ClientConnectOpts) -> Result<(), ClientError> {
         let verbose = client_connect_opts.verbose;
-        if let Err(e) = self.main_tx.send(Connect { client_id, client_connect_opts}).await {
+        let client_auth = match (&self.config.authorization, &context.cert_identities) {
+            _ if context.no_auth => ClientAuth::default(),
+            (Some(authorization), Some(cert_identities)) => authorization.authenticate_cert(cert_identities)
+                .ok_or(ClientError::AuthorizationViolation)?,
+            (Some(authorization), None) => {
+                // bcrypt and the account JWT resolver block, so they run outside of the async workers
+                let authorization = authorization.clone();
+                let opts = client_connect_opts.clone();
+                let nonce = context.nonce.clone();
+                tokio::task::spawn_blocking(move || authorization.authenticate(&opts, nonce.as_deref())).await
+                    .unwrap_or_else(|e| {
+                        error!("error authenticating client {}: {}", client_id, e);
+                        None
+                    })
+                    .ok_or(ClientError::AuthorizationViolation)?
+            }
+            (None, _) => ClientAuth::default(),
+        };
+        debug!("client id {} authenticated as {:?}", client_id, client_auth.user);
+        let (processed, connect_processed) = oneshot::channel();
+        if let Err(e) = self.main_tx.send(Connect { client_id, client_connect_opts, client_auth: Box::new(client_auth), processed }).await {
             error!("error sending to main channel: {}", e);
         }
+        // wait for the client state to be stored, otherwise a PUB right after CONNECT may be rejected
+        if !connect_processed.await.unwrap_or(false) {
+            return Err(ClientError::MaxAccountConnectionsExceeded);
+        }
         if verbose {
             socket.write_all(b"+OK\r\n").await?;
         }
         Ok(())
     }
 
-    async fn handle_ping(&self, _: u32, socket: &mut TcpStream) -> Result<(), Error> {
+    async fn handle_ping(&self, _: u32, socket: &mut (impl AsyncWrite + Unpin)) -> Result<(), ClientError> {
         socket.write_all(b"PONG\r\n").await?;
         Ok(())
     }
 
-    async fn handle_pub(&self, client_id: u32, subject: String, msg: String, socket: &mut TcpStream) -> Result<(), Error> {
+    async fn handle_pub(&self, client_id: u32, subject: String, reply_to: Option<String>, headers: Option<Bytes>, msg: Bytes, socket: &mut (impl AsyncWrite + Unpin)) -> Result<(), ClientError> {
         self.check_client_connected(client_id).await?;
+        if !is_valid_publish_subject(&subject) {
+            return Err(ClientError::InvalidPublishSubject);
+        }
+        if !self.check_client_permissions(client_id, |permissions| permissions.can_publish(&subject)).await {
+            return Err(ClientError::PublishPermissionViolation(subject));
+        }
+        if let Some(reply_to) = &reply_to {
+            if self.check_client_pedantic(client_id).await? && !is_valid_publish_subject(reply_to) {
+                return Err(ClientError::InvalidPublishSubject);
+            }
+        }
         info!("publishing to {}", subject);
 
-        if let Err(e) = self.main_tx.send(MainCommand::Publish { subject, msg }).await {
+        if let Err(e) = self.main_tx.send(MainCommand::Publish { client_id, subject, reply_to, headers, msg }).await {
             error!("error sending to main channel: {}", e);
         }
         if self.check_client_verbose(client_id).await? {
@@ -149,10 +411,16 @@ impl Server {
         Ok(())
     }
 
-    async fn handle_sub(&self, client_id: u32, subject: String, subscription_id: String, socket: &mut TcpStream) -> Result<(), Error> {
+    async fn handle_sub(&self, client_id: u32, subject: String, queue_group: Option<String>, subscription_id: String, socket: &mut (impl AsyncWrite + Unpin)) -> Result<(), ClientError> {
         self.check_client_connected(client_id).await?;
+        if !is_valid_subscription_subject(&subject) {
+            return Err(ClientError::InvalidSubject);
+        }
+        if !self.check_client_permissions(client_id, |permissions| permissions.can_subscribe(&subject)).await {
+            return Err(ClientError::SubscribePermissionViolation(subject));
+        }
         info!("client_id {} subscribing to {} (id: {})", client_id, subject, subscription_id);
-        if let Err(e) = self.main_tx.send(MainCommand::Subscribe { subject, client_id, subscription_id }).await {
+        if let Err(e) = self.main_tx.send(MainCommand::Subscribe { subject, client_id, queue_group, subscription_id }).await {
             error!("error sending to main channel: {}", e);
         }
         if self.check_client_verbose(client_id).await? {
@@ -161,10 +429,10 @@ impl Server {
         Ok(())
     }
 
-    async fn handle_unsub(&self, client_id: u32, subscription_id: String, socket: &mut TcpStream) -> Result<(), Error> {
+    async fn handle_unsub(&self, client_id: u32, subscription_id: String, max_msgs: Option<u32>, socket: &mut (impl AsyncWrite + Unpin)) -> Result<(), ClientError> {
         self.check_client_connected(client_id).await?;
         info!("client_id {} unsubscribing to {} ", client_id, subscription_id);
-        if let Err(e) = self.main_tx.send(MainCommand::Unsubscribe { client_id, subscription_id }).await {
+        if let Err(e) = self.main_tx.send(MainCommand::Unsubscribe { client_id, subscription_id, max_msgs }).await {
             error!("error sending to main channel: {}", e);
         }
         if self.check_client_verbose(client_id).await? {
@@ -173,41 +441,132 @@ impl Server {
         Ok(())
     }
 
-    async fn handle_commands(&self, cmd: ClientCommand, socket: &mut TcpStream, client_id: u32) {
-        let cmd_result = match cmd {
+    async fn handle_commands(&self, cmd: ClientCommand, socket: &mut (impl AsyncWrite + Unpin), client_id: u32, context: &ConnectionContext) -> Result<(), ClientError> {
+        match cmd {
             ClientCommand::Noop => { Ok(()) }
-            ClientCommand::Connect(opts) => self.handle_connect(client_id, socket, opts).await,
-            ClientCommand::Pub { subject, msg } => self.handle_pub(client_id, subject, msg, socket).await,
-            ClientCommand::Sub { subject, id } => self.handle_sub(client_id, subject, id, socket).await,
-            ClientCommand::Unsub { id } => self.handle_unsub(client_id, id, socket).await,
+            ClientCommand::Connect(opts) => self.handle_connect(client_id, context, socket, opts).await,
+            ClientCommand::Pub { subject, reply_to, headers, msg } => self.handle_pub(client_id, subject, reply_to, headers, msg, socket).await,
+            ClientCommand::Sub { subject, queue_group, id } => self.handle_sub(client_id, subject, queue_group, id, socket).await,
+            ClientCommand::Unsub { id, max_msgs } => self.handle_unsub(client_id, id, max_msgs, socket).await,
             ClientCommand::Ping => self.handle_ping(client_id, socket).await,
             ClientCommand::Pong => { Ok(()) }
-        };
+        }
+    }
+
+    async fn handle_error(&self, client_id: u32, socket: &mut (impl AsyncWrite + Unpin), e: &ClientError) {
+        error!("error for client {}: {:?}", client_id, e);
+        if let ClientError::Io(_) = e {
+            // the socket is unusable, there is no point reporting it to the client
+            return;
+        }
+        let response = format!("-ERR '{}'\r\n", e);
+        if let Err(e) = socket.write_all(response.as_bytes()).await {
+            error!("error writing to socket: {}", e);
+        }
+    }
+
+    async fn check_client_connected(&self, client_id: u32) -> Result<(), ClientError> {
+        let clients_tx = self.clients_tx.read().await;
+        match clients_tx.get(&client_id) {
+            Some((_, client_state)) if client_state.connected => Ok(()),
+            _ => Err(ClientError::AuthorizationViolation),
+        }
+    }
 
-        if let Some(e) = cmd_result.err() {
-            error!("error: {:?}", e);
-            let _ = socket.write_all(b"-ERR\n").await;
+    async fn check_client_verbose(&self, client_id: u32) -> Result<bool, ClientError> {
+        let clients_tx = self.clients_tx.read().await;
+        if let Some((_, client_state)) = clients_tx.get(&client_id) {
+            Ok(client_state.connect_opts.verbose)
+        } else {
+            Ok(false)
         }
     }
 
-    async fn check_client_connected(&self, client_id: u32) -> Result<(), Error> {
+    // clients without permissions are unrestricted
+    async fn check_client_permissions(&self, client_id: u32, check: impl Fn(&Permissions) -> bool) -> bool {
         let clients_tx = self.clients_tx.read().await;
         clients_tx.get(&client_id)
-            .ok_or(Error::new(NotConnected, "client is not connected"))
-            .and_then(|(_, client_state)| {
-                if !client_state.connected {
-                    return Err(Error::new(NotConnected, "client is not connected"));
-                }
-                Ok(())
-            })
+            .and_then(|(_, client_state)| client_state.permissions.as_ref())
+            .map(check)
+            .unwrap_or(true)
     }
 
-    async fn check_client_verbose(&self, client_id: u32) -> Result<bool, Error> {
+    async fn check_client_pedantic(&self, client_id: u32) -> Result<bool, ClientError> {
         let clients_tx = self.clients_tx.read().await;
         if let Some((_, client_state)) = clients_tx.get(&client_id) {
-            Ok(client_state.verbose)
+            Ok(client_state.connect_opts.pedantic)
         } else {
             Ok(false)
         }
     }
 }
+
+async fn write_info(socket: &mut (impl AsyncWrite + Unpin), info: &ServerInfo) -> Result<(), io::Error> {
+    let response = format!("INFO {}\r\n", serde_json::to_string(info)?);
+    socket.write_all(response.as_bytes()).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_keepalive_stale_after_ping_max() {
+        let mut keepalive = Keepalive::new(2);
+        assert!(keepalive.ping());
+        assert!(keepalive.ping());
+        assert_eq!(2, keepalive.pings_outstanding());
+        assert!(!keepalive.ping());
+        assert_eq!(2, keepalive.pings_outstanding());
+    }
+
+    #[test]
+    fn test_keepalive_pong_resets_outstanding() {
+        let mut keepalive = Keepalive::new(2);
+        assert!(keepalive.ping());
+        assert!(keepalive.ping());
+        keepalive.pong();
+        assert_eq!(0, keepalive.pings_outstanding());
+        assert!(keepalive.ping());
+        assert!(keepalive.ping());
+        assert!(!keepalive.ping());
+    }
+
+    #[test_case(ClientError::UnknownProtocolOperation, "Unknown Protocol Operation", true; "unknown protocol operation")]
+    #[test_case(ClientError::MaxPayloadViolation, "Maximum Payload Violation", true; "max payload")]
+    #[test_case(ClientError::MaxControlLineExceeded, "Maximum Control Line Exceeded", true; "max control line")]
+    #[test_case(ClientError::AuthorizationViolation, "Authorization Violation", true; "authorization violation")]
+    #[test_case(ClientError::AuthenticationExpired, "User Authentication Expired", true; "authentication expired")]
+    #[test_case(ClientError::SecureConnectionRequired, "Secure Connection - TLS Required", true; "tls required")]
+    #[test_case(ClientError::StaleConnection, "Stale Connection", true; "stale connection")]
+    #[test_case(ClientError::InvalidSubject, "Invalid Subject", false; "invalid subject")]
+    #[test_case(ClientError::InvalidPublishSubject, "Invalid Publish Subject", false; "invalid publish subject")]
+    #[test_case(ClientError::PublishPermissionViolation("foo".to_string()), "Permissions Violation for Publish to foo", false; "publish permission")]
+    #[test_case(ClientError::SubscribePermissionViolation("foo".to_string()), "Permissions Violation for Subscription to foo", false; "subscribe permission")]
+    fn test_client_error(e: ClientError, expected: &str, fatal: bool) {
+        assert_eq!(expected, e.to_string());
+        assert_eq!(fatal, e.is_fatal());
+    }
+
+    #[test_case(ParseError::MaxPayloadExceeded, "Maximum Payload Violation"; "max payload")]
+    #[test_case(ParseError::MaxControlLineExceeded, "Maximum Control Line Exceeded"; "max control line")]
+    #[test_case(ParseError::InvalidInput, "Unknown Protocol Operation"; "invalid input")]
+    fn test_client_error_from_parse_error(e: ParseError, expected: &str) {
+        assert_eq!(expected, ClientError::from(e).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_handle_error() {
+        let config = toml::from_str(r#"listeners = [{ address = "127.0.0.1:4222" }]"#).unwrap();
+        let (server, _) = Server::new(config);
+        let mut socket = vec![];
+        server.handle_error(1, &mut socket, &ClientError::PublishPermissionViolation("foo".to_string())).await;
+        assert_eq!(b"-ERR 'Permissions Violation for Publish to foo'\r\n".to_vec(), socket);
+
+        // io errors are not reported to the client
+        let mut socket = vec![];
+        server.handle_error(1, &mut socket, &ClientError::Io(io::ErrorKind::BrokenPipe.into())).await;
+        assert!(socket.is_empty());
+    }
+}
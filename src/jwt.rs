@@ -0,0 +1,399 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use nkeys::KeyPair;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use thiserror::Error;
+use crate::config::{Permissions, SubjectPermission};
+
+const JWT_TYPE: &str = "JWT";
+// v2 JWTs use ed25519-nkey, v1 used ed25519, the signature scheme is the same
+const JWT_ALGORITHMS: [&str; 2] = ["ed25519-nkey", "ed25519"];
+const USER_CLAIM_TYPE: &str = "user";
+const ACCOUNT_CLAIM_TYPE: &str = "account";
+const NO_LIMIT: i64 = -1;
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("malformed jwt")]
+    Malformed,
+    #[error("unsupported jwt header")]
+    UnsupportedHeader,
+    #[error("invalid jwt signature")]
+    InvalidSignature,
+    #[error("expected {0} claims")]
+    UnexpectedClaimType(&'static str),
+    #[error("jwt has expired")]
+    Expired,
+    #[error("jwt is not valid yet")]
+    NotYetValid,
+    #[error("untrusted issuer {0}")]
+    UntrustedIssuer(String),
+    #[error("invalid account {0}")]
+    InvalidAccount(String),
+    #[error("no account resolver configured")]
+    NoResolver,
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    typ: String,
+    alg: String,
+}
+
+// registered claims shared by every JWT, the NATS specific claims live under `nats`
+#[derive(Debug, Deserialize)]
+pub struct Claims<T> {
+    pub iss: String,
+    pub sub: String,
+    pub exp: Option<u64>,
+    pub nbf: Option<u64>,
+    pub nats: T,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UserClaims {
+    #[serde(rename = "type")]
+    pub claim_type: String,
+    // set when the user was signed by one of the account signing keys
+    pub issuer_account: Option<String>,
+    #[serde(rename = "pub")]
+    pub publish: JwtPermission,
+    #[serde(rename = "sub")]
+    pub subscribe: JwtPermission,
+    // bearer tokens skip the nonce signature
+    pub bearer_token: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct JwtPermission {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AccountClaims {
+    #[serde(rename = "type")]
+    pub claim_type: String,
+    pub signing_keys: Vec<SigningKey>,
+    pub limits: AccountLimits,
+}
+
+// scoped signing keys are objects, only the key itself is used here
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SigningKey {
+    Key(String),
+    Scoped { key: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AccountLimits {
+    pub conn: i64,
+}
+
+impl Default for AccountLimits {
+    fn default() -> Self {
+        AccountLimits { conn: NO_LIMIT }
+    }
+}
+
+impl<T> Claims<T> {
+    fn check_validity(&self, now: u64) -> Result<(), JwtError> {
+        if self.exp.is_some_and(|exp| exp <= now) {
+            return Err(JwtError::Expired);
+        }
+        if self.nbf.is_some_and(|nbf| nbf > now) {
+            return Err(JwtError::NotYetValid);
+        }
+        Ok(())
+    }
+}
+
+impl UserClaims {
+    // empty allow lists mean everything is allowed
+    pub fn permissions(&self) -> Option<Permissions> {
+        if self.publish.is_empty() && self.subscribe.is_empty() {
+            return None;
+        }
+        Some(Permissions {
+            publish: self.publish.to_subject_permission(),
+            subscribe: self.subscribe.to_subject_permission(),
+        })
+    }
+}
+
+impl JwtPermission {
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    fn to_subject_permission(&self) -> SubjectPermission {
+        SubjectPermission {
+            allow: (!self.allow.is_empty()).then(|| self.allow.clone()),
+            deny: self.deny.clone(),
+        }
+    }
+}
+
+impl AccountClaims {
+    fn is_signing_key(&self, key: &str) -> bool {
+        self.signing_keys.iter().any(|signing_key| match signing_key {
+            SigningKey::Key(k) | SigningKey::Scoped { key: k } => k == key,
+        })
+    }
+
+    pub fn max_connections(&self) -> Option<u32> {
+        u32::try_from(self.limits.conn).ok()
+    }
+}
+
+// account JWTs stored as `<account public key>.jwt` in a local directory
+#[derive(Debug)]
+pub struct DirResolver {
+    dir: PathBuf,
+}
+
+impl DirResolver {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirResolver { dir: dir.into() }
+    }
+
+    pub fn fetch(&self, account: &str) -> Result<String, JwtError> {
+        // the account comes from a client supplied JWT, never let it escape the directory
+        if !account.starts_with('A') || !account.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(JwtError::InvalidAccount(account.to_string()));
+        }
+        let jwt = fs::read_to_string(self.dir.join(format!("{}.jwt", account)))?;
+        Ok(jwt.trim().to_string())
+    }
+}
+
+// verified user and account claims for a user JWT presented in CONNECT
+#[derive(Debug)]
+pub struct VerifiedUser {
+    pub user: Claims<UserClaims>,
+    pub account: Claims<AccountClaims>,
+}
+
+impl VerifiedUser {
+    pub fn account_id(&self) -> &str {
+        &self.account.sub
+    }
+
+    // the credentials stop being valid as soon as either JWT expires
+    pub fn expires(&self) -> Option<u64> {
+        match (self.user.exp, self.account.exp) {
+            (Some(user), Some(account)) => Some(user.min(account)),
+            (user, account) => user.or(account),
+        }
+    }
+}
+
+// walks the chain user -> account -> operator, the operator must be one of the trusted keys
+pub fn verify_user_jwt(user_jwt: &str, trusted_keys: &[String], resolver: &DirResolver) -> Result<VerifiedUser, JwtError> {
+    let now = now();
+    let user: Claims<UserClaims> = decode(user_jwt)?;
+    if user.nats.claim_type != USER_CLAIM_TYPE {
+        return Err(JwtError::UnexpectedClaimType(USER_CLAIM_TYPE));
+    }
+    user.check_validity(now)?;
+
+    let account_id = user.nats.issuer_account.as_deref().unwrap_or(&user.iss);
+    let account: Claims<AccountClaims> = decode(&resolver.fetch(account_id)?)?;
+    if account.nats.claim_type != ACCOUNT_CLAIM_TYPE {
+        return Err(JwtError::UnexpectedClaimType(ACCOUNT_CLAIM_TYPE));
+    }
+    if account.sub != account_id {
+        return Err(JwtError::InvalidAccount(account_id.to_string()));
+    }
+    account.check_validity(now)?;
+    if !trusted_keys.contains(&account.iss) {
+        return Err(JwtError::UntrustedIssuer(account.iss));
+    }
+    if user.iss != account.sub && !account.nats.is_signing_key(&user.iss) {
+        return Err(JwtError::UntrustedIssuer(user.iss));
+    }
+
+    Ok(VerifiedUser { user, account })
+}
+
+fn decode<T: DeserializeOwned>(jwt: &str) -> Result<Claims<T>, JwtError> {
+    let mut parts = jwt.split('.');
+    let (Some(header), Some(payload), Some(sig), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(JwtError::Malformed);
+    };
+
+    let header_json: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    if !header_json.typ.eq_ignore_ascii_case(JWT_TYPE) || !JWT_ALGORITHMS.iter().any(|alg| header_json.alg.eq_ignore_ascii_case(alg)) {
+        return Err(JwtError::UnsupportedHeader);
+    }
+
+    let claims: Claims<T> = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    let sig = URL_SAFE_NO_PAD.decode(sig)?;
+    let signed = &jwt[..header.len() + 1 + payload.len()];
+    KeyPair::from_public_key(&claims.iss)
+        .and_then(|issuer| issuer.verify(signed.as_bytes(), &sig))
+        .map_err(|_| JwtError::InvalidSignature)?;
+    Ok(claims)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::{json, Value};
+    use test_case::test_case;
+
+    pub fn encode(claims: Value, issuer: &KeyPair) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"typ": "JWT", "alg": "ed25519-nkey"}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{}.{}", header, payload);
+        let sig = URL_SAFE_NO_PAD.encode(issuer.sign(signed.as_bytes()).unwrap());
+        format!("{}.{}", signed, sig)
+    }
+
+    // operator, account and user keys with the account JWT stored in a fresh resolver directory
+    pub struct Chain {
+        pub operator: KeyPair,
+        pub account: KeyPair,
+        pub user: KeyPair,
+        pub resolver: DirResolver,
+    }
+
+    impl Chain {
+        pub fn new(account_nats: Value) -> Self {
+            let operator = KeyPair::new_operator();
+            let account = KeyPair::new_account();
+            let user = KeyPair::new_user();
+            let dir = std::env::temp_dir().join(format!("challenge_nats_jwt_{}", user.public_key()));
+            fs::create_dir_all(&dir).unwrap();
+            let account_jwt = encode(json!({"iss": operator.public_key(), "sub": account.public_key(), "nats": account_nats}), &operator);
+            fs::write(dir.join(format!("{}.jwt", account.public_key())), account_jwt).unwrap();
+            Chain { operator, account, user, resolver: DirResolver::new(dir) }
+        }
+
+        pub fn resolver_dir(&self) -> String {
+            self.resolver.dir.to_string_lossy().to_string()
+        }
+
+        pub fn trusted_keys(&self) -> Vec<String> {
+            vec![self.operator.public_key()]
+        }
+
+        pub fn user_jwt(&self, user_nats: Value) -> String {
+            encode(json!({"iss": self.account.public_key(), "sub": self.user.public_key(), "nats": user_nats}), &self.account)
+        }
+    }
+
+    impl Drop for Chain {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.resolver.dir);
+        }
+    }
+
+    #[test]
+    fn test_verify_user_jwt() {
+        let chain = Chain::new(json!({"type": "account", "limits": {"conn": 2}}));
+        let jwt = chain.user_jwt(json!({"type": "user", "pub": {"allow": ["foo.>"]}, "sub": {"deny": ["secret"]}}));
+        let verified = verify_user_jwt(&jwt, &chain.trusted_keys(), &chain.resolver).unwrap();
+        assert_eq!(chain.user.public_key(), verified.user.sub);
+        assert_eq!(chain.account.public_key(), verified.account_id());
+        assert_eq!(Some(2), verified.account.nats.max_connections());
+        let permissions = verified.user.nats.permissions().unwrap();
+        assert_eq!(Some(vec!["foo.>".to_string()]), permissions.publish.allow);
+        assert_eq!(None, permissions.subscribe.allow);
+        assert_eq!(vec!["secret".to_string()], permissions.subscribe.deny);
+    }
+
+    #[test]
+    fn test_verify_user_jwt_without_limits() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let verified = verify_user_jwt(&chain.user_jwt(json!({"type": "user"})), &chain.trusted_keys(), &chain.resolver).unwrap();
+        assert_eq!(None, verified.account.nats.max_connections());
+        assert!(verified.user.nats.permissions().is_none());
+        assert_eq!(None, verified.expires());
+    }
+
+    #[test]
+    fn test_verify_user_jwt_signing_key() {
+        let signing_key = KeyPair::new_account();
+        let chain = Chain::new(json!({"type": "account", "signing_keys": [signing_key.public_key()]}));
+        let claims = json!({"iss": signing_key.public_key(), "sub": chain.user.public_key(),
+            "nats": {"type": "user", "issuer_account": chain.account.public_key()}});
+        assert!(verify_user_jwt(&encode(claims, &signing_key), &chain.trusted_keys(), &chain.resolver).is_ok());
+    }
+
+    #[test]
+    fn test_verify_user_jwt_unknown_signing_key() {
+        let signing_key = KeyPair::new_account();
+        let chain = Chain::new(json!({"type": "account"}));
+        let claims = json!({"iss": signing_key.public_key(), "sub": chain.user.public_key(),
+            "nats": {"type": "user", "issuer_account": chain.account.public_key()}});
+        assert!(matches!(verify_user_jwt(&encode(claims, &signing_key), &chain.trusted_keys(), &chain.resolver),
+            Err(JwtError::UntrustedIssuer(_))));
+    }
+
+    #[test]
+    fn test_verify_user_jwt_untrusted_operator() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let trusted_keys = vec![KeyPair::new_operator().public_key()];
+        assert!(matches!(verify_user_jwt(&chain.user_jwt(json!({"type": "user"})), &trusted_keys, &chain.resolver),
+            Err(JwtError::UntrustedIssuer(_))));
+    }
+
+    #[test]
+    fn test_verify_user_jwt_expired() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let claims = json!({"iss": chain.account.public_key(), "sub": chain.user.public_key(), "exp": now() - 1, "nats": {"type": "user"}});
+        assert!(matches!(verify_user_jwt(&encode(claims, &chain.account), &chain.trusted_keys(), &chain.resolver),
+            Err(JwtError::Expired)));
+    }
+
+    #[test]
+    fn test_verify_user_jwt_tampered() {
+        let chain = Chain::new(json!({"type": "account"}));
+        let jwt = chain.user_jwt(json!({"type": "user", "pub": {"allow": ["foo"]}}));
+        let mut parts: Vec<&str> = jwt.split('.').collect();
+        let tampered_payload = URL_SAFE_NO_PAD.encode(json!({"iss": chain.account.public_key(), "sub": chain.user.public_key(), "nats": {"type": "user"}}).to_string());
+        parts[1] = &tampered_payload;
+        assert!(matches!(verify_user_jwt(&parts.join("."), &chain.trusted_keys(), &chain.resolver),
+            Err(JwtError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_verify_user_jwt_wrong_claim_type() {
+        let chain = Chain::new(json!({"type": "account"}));
+        assert!(matches!(verify_user_jwt(&chain.user_jwt(json!({"type": "account"})), &chain.trusted_keys(), &chain.resolver),
+            Err(JwtError::UnexpectedClaimType(USER_CLAIM_TYPE))));
+    }
+
+    #[test]
+    fn test_dir_resolver_rejects_paths() {
+        let resolver = DirResolver::new(std::env::temp_dir());
+        assert!(matches!(resolver.fetch("A../../etc/passwd"), Err(JwtError::InvalidAccount(_))));
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("a.b"; "missing signature")]
+    #[test_case("a.b.c.d"; "too many parts")]
+    fn test_decode_malformed(jwt: &str) {
+        assert!(matches!(decode::<UserClaims>(jwt), Err(JwtError::Malformed)));
+    }
+}
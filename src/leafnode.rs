@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use log::{debug, warn};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep, Duration};
+use crate::config::LinkAuthorization;
+use crate::route::{Interest, Link, RouteCommand, RouteInfo};
+use crate::server::{Server, GLOBAL_ACCOUNT};
+
+// delay before a remote leaf node connection is opened again
+const LEAFNODE_CONNECT_RETRY: Duration = Duration::from_secs(2);
+
+// a server connected as a leaf node or the hub of this server, only the subscriptions and messages
+// of its account are shared, unlike routes it is not part of the full mesh so interest received
+// from it is passed on to the routes and the other leaf nodes
+#[derive(Debug)]
+pub struct Leafnode {
+    pub leafnode_id: u32,
+    pub server_id: String,
+    // the server and the other servers of its cluster
+    pub cluster: HashSet<String>,
+    pub account: String,
+    pub tx: UnboundedSender<RouteCommand>,
+    // interest received from the leaf node
+    pub interest: HashSet<Interest>,
+    // interest as last sent to the leaf node
+    pub advertised_interest: HashSet<Interest>,
+}
+
+impl Server {
+    // leaf nodes take no part in the gossip, so only the identity of the server and its cluster is sent
+    pub async fn leafnode_info(&self) -> RouteInfo {
+        RouteInfo {
+            server_id: self.server_id.clone(),
+            server_name: self.config.server_name.clone().unwrap_or_else(|| self.server_id.clone()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            cluster: self.routes.read().await.keys().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    // remote hubs are kept connected for the lifetime of the server
+    pub async fn connect_leafnodes(self: &Arc<Self>) {
+        let Some(leafnodes) = &self.config.leafnodes else {
+            return;
+        };
+        for remote in &leafnodes.remotes {
+            let url = remote.url.clone();
+            let account = remote.account.clone().unwrap_or_else(|| GLOBAL_ACCOUNT.to_string());
+            let credentials = remote.credentials.clone();
+            let server = self.clone();
+            tokio::spawn(async move {
+                server.solicit_leafnode(url, account, credentials).await;
+            });
+        }
+    }
+
+    async fn solicit_leafnode(&self, url: String, account: String, credentials: Option<LinkAuthorization>) {
+        loop {
+            match TcpStream::connect(&url).await {
+                Ok(socket) => {
+                    let link = Link::Leafnode { account: account.clone(), solicited: true, credentials: credentials.clone() };
+                    if let Some(server_id) = self.handle_link(socket, link).await {
+                        if server_id == self.server_id {
+                            warn!("leaf node remote {} points to this server", url);
+                            return;
+                        }
+                        // the server may already be connected through another link
+                        while self.leafnodes.read().await.values().any(|leafnode| leafnode.cluster.contains(&server_id)) {
+                            sleep(LEAFNODE_CONNECT_RETRY).await;
+                        }
+                    }
+                }
+                Err(e) => debug!("unable to connect leaf node remote {}: {}", url, e),
+            }
+            sleep(LEAFNODE_CONNECT_RETRY).await;
+        }
+    }
+
+    // leaf nodes connecting to this server are bound to the account of the leaf node listener
+    pub async fn handle_leafnode(&self, socket: TcpStream) {
+        let account = self.config.leafnodes.as_ref()
+            .and_then(|leafnodes| leafnodes.account.clone())
+            .unwrap_or_else(|| GLOBAL_ACCOUNT.to_string());
+        self.handle_link(socket, Link::Leafnode { account, solicited: false, credentials: None }).await;
+    }
+}
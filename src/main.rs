@@ -1,17 +1,29 @@
 mod parser;
+mod account;
+mod auth;
+mod jwt;
 mod config;
 mod server;
+mod route;
+mod leafnode;
 pub mod commands;
 mod handlers;
+mod subject;
+mod tls;
+mod websocket;
 
 use crate::server::Server;
 use env_logger::Env;
 use log::{error, info, warn};
 use std::env;
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
@@ -24,6 +36,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .default_filter_or("warn"))
         .init();
 
+    if env::args().nth(1).as_deref() == Some("passwd") {
+        return print_password_hash(env::args().nth(2));
+    }
+
     let conf_path = env::args().nth(1).unwrap_or_else(|| { "config.toml".to_string() });
     let conf = config::parse_config(&conf_path);
 
@@ -32,8 +48,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
         signal_handlers(shutdown_tx).await;
     });
 
-    let listener = TcpListener::bind(conf.listener).await?;
-    let (server, main_rx) = Server::new();
+    // every listener accepts in its own task and hands the connections over to the loop below
+    let (connection_tx, mut connection_rx) = mpsc::channel::<Connection>(100);
+    for (index, listener) in conf.listeners.iter().enumerate() {
+        let tcp_listener = TcpListener::bind(&listener.address).await?;
+        info!("listening on {}", listener.address);
+        spawn_accept(tcp_listener, connection_tx.clone(), move |socket| Connection::Tcp(socket, index));
+    }
+    if let Some(websocket) = &conf.websocket {
+        let tcp_listener = TcpListener::bind(&websocket.listener).await?;
+        info!("listening for websocket clients on {}", websocket.listener);
+        spawn_accept(tcp_listener, connection_tx.clone(), Connection::WebSocket);
+    }
+    if let Some(cluster) = &conf.cluster {
+        let tcp_listener = TcpListener::bind(&cluster.listener).await?;
+        info!("listening for routes on {}", cluster.listener);
+        spawn_accept(tcp_listener, connection_tx.clone(), Connection::Route);
+    }
+    if let Some(listener) = conf.leafnodes.as_ref().and_then(|leafnodes| leafnodes.listener.as_ref()) {
+        let tcp_listener = TcpListener::bind(listener).await?;
+        info!("listening for leaf nodes on {}", listener);
+        spawn_accept(tcp_listener, connection_tx.clone(), Connection::Leafnode);
+    }
+    if let Some(path) = &conf.unix_socket {
+        remove_stale_socket(path)?;
+        let unix_listener = UnixListener::bind(path)?;
+        info!("listening on unix socket {}", path);
+        spawn_accept_unix(unix_listener, connection_tx.clone());
+    }
+    drop(connection_tx);
+
+    let (server, main_rx) = Server::new(conf);
     let server = Arc::new(server);
 
     let server_arc = server.clone();
@@ -43,21 +88,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
     handles.push(handle);
 
+    // configured routes are kept connected for the lifetime of the server
+    if let Some(cluster) = &server.config.cluster {
+        server.connect_routes(cluster.routes.clone(), false).await;
+    }
+    server.connect_leafnodes().await;
+
     loop {
         tokio::select! {
-            result = listener.accept() => {
-                match result {
-                    Ok((socket, _)) => {
-                        let server = server.clone();
-                        let handle = tokio::spawn(async move {
-                            server.handle(socket).await;
-                        });
-                        handles.push(handle);
-                    }
-                    Err(e) => {
-                        error!("error accepting connection {:?}", e);
+            Some(connection) = connection_rx.recv() => {
+                let server = server.clone();
+                let handle = tokio::spawn(async move {
+                    match connection {
+                        Connection::Tcp(socket, listener) => server.handle_tcp(socket, listener).await,
+                        Connection::WebSocket(socket) => server.handle_websocket(socket).await,
+                        Connection::Unix(socket) => server.handle(socket, None).await,
+                        Connection::Route(socket) => {
+                            server.handle_route(socket, false).await;
+                        }
+                        Connection::Leafnode(socket) => server.handle_leafnode(socket).await,
                     }
-                }
+                });
+                handles.push(handle);
             }
 
             _ = shutdown_rx.recv() => {
@@ -81,6 +133,85 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if let Some(path) = &server.config.unix_socket {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("unable to remove unix socket {}: {}", path, e);
+        }
+    }
+
+    Ok(())
+}
+
+// accepted client connection, tagged with the listener it came from
+enum Connection {
+    // index of the listener in the config
+    Tcp(TcpStream, usize),
+    WebSocket(TcpStream),
+    Unix(UnixStream),
+    Route(TcpStream),
+    Leafnode(TcpStream),
+}
+
+fn spawn_accept(listener: TcpListener, tx: Sender<Connection>, connection: impl Fn(TcpStream) -> Connection + Send + 'static) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    if tx.send(connection(socket)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("error accepting connection {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+fn spawn_accept_unix(listener: UnixListener, tx: Sender<Connection>) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    if tx.send(Connection::Unix(socket)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("error accepting unix socket connection {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+// a previous run that did not shut down cleanly leaves the socket file behind, anything else at
+// the path, including the socket of a running server, is kept
+fn remove_stale_socket(path: &str) -> Result<(), Box<dyn Error>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => Err(format!("unix_socket {} exists and is not a socket", path).into()),
+        Ok(_) if StdUnixStream::connect(path).is_ok() => Err(format!("unix_socket {} is in use by another server", path).into()),
+        Ok(_) => Ok(fs::remove_file(path)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// prints a bcrypt hash to be used as a password in the config, reads the password from stdin if not given
+fn print_password_hash(password: Option<String>) -> Result<(), Box<dyn Error>> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        return Err("password must not be empty".into());
+    }
+    println!("{}", auth::hash_password(&password)?);
     Ok(())
 }
 
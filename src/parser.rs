@@ -1,11 +1,12 @@
-use std::str::from_utf8;
+use std::fmt;
+use bytes::Bytes;
 use log::{error};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use ParserState::*;
 use crate::commands::ClientCommand;
 use crate::commands::ClientCommand::*;
-use crate::parser::ParseError::{InvalidInput, NotAPositiveInt};
+use crate::parser::ParseError::{InvalidInput, MaxControlLineExceeded, MaxPayloadExceeded, NotAPositiveInt};
 
 #[derive(Debug, PartialEq, Eq)]
 enum ParserState {
@@ -32,6 +33,12 @@ enum ParserState {
     PubArg,
     PubMsg,
 
+    OpH,
+    OpHp,
+    OpHpu,
+    OpHpub,
+    HpubArg,
+
     OpS,
     OpSu,
     OpSub,
@@ -52,8 +59,17 @@ pub enum ParseError {
     InvalidInput,
     #[error("not a positive int")]
     NotAPositiveInt,
+    #[error("maximum payload exceeded")]
+    MaxPayloadExceeded,
+    #[error("maximum control line exceeded")]
+    MaxControlLineExceeded,
 }
 
+const HEADER_VERSION: &[u8] = b"NATS/1.0";
+
+pub const DEFAULT_MAX_PAYLOAD: usize = 1048576;
+pub const DEFAULT_MAX_CONTROL_LINE: usize = 4096;
+
 fn split_arg(buf: &[char]) -> Vec<Vec<char>> {
     let mut result: Vec<Vec<char>> = Vec::new();
     let mut start = None;
@@ -79,13 +95,14 @@ fn split_arg(buf: &[char]) -> Vec<Vec<char>> {
 }
 
 fn parse_uint(chars: &[char]) -> Result<u32, ParseError> {
-    let mut number = 0;
+    let mut number: u32 = 0;
     for c in chars {
-        if let Some(digit) = c.to_digit(10) {
-            number = number * 10 + digit;
-        } else {
+        let Some(digit) = c.to_digit(10) else {
             return Err(NotAPositiveInt);
-        }
+        };
+        number = number.checked_mul(10)
+            .and_then(|number| number.checked_add(digit))
+            .ok_or(NotAPositiveInt)?;
     }
     Ok(number)
 }
@@ -95,13 +112,76 @@ pub struct ClientRequest {
     arg_buffer: Vec<char>,
     msg_buffer: Vec<u8>,
     msg_size: usize,
+    hdr_size: Option<usize>,
     args: Vec<Vec<char>>,
+    max_payload: usize,
+    max_control_line: usize,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
 pub struct ClientConnectOpts {
-    #[serde(default)]
     pub verbose: bool,
+    pub pedantic: bool,
+    pub echo: bool,
+    pub name: Option<String>,
+    pub lang: Option<String>,
+    pub version: Option<String>,
+    pub protocol: u32,
+    pub headers: bool,
+    pub no_responders: bool,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub auth_token: Option<String>,
+    pub nkey: Option<String>,
+    pub sig: Option<String>,
+    pub jwt: Option<String>,
+}
+
+impl Default for ClientConnectOpts {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            pedantic: false,
+            // clients receive their own publishes unless they opt out
+            echo: true,
+            name: None,
+            lang: None,
+            version: None,
+            protocol: 0,
+            headers: false,
+            no_responders: false,
+            user: None,
+            pass: None,
+            auth_token: None,
+            nkey: None,
+            sig: None,
+            jwt: None,
+        }
+    }
+}
+
+// credentials are redacted as commands are logged
+impl fmt::Debug for ClientConnectOpts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConnectOpts")
+            .field("verbose", &self.verbose)
+            .field("pedantic", &self.pedantic)
+            .field("echo", &self.echo)
+            .field("name", &self.name)
+            .field("lang", &self.lang)
+            .field("version", &self.version)
+            .field("protocol", &self.protocol)
+            .field("headers", &self.headers)
+            .field("no_responders", &self.no_responders)
+            .field("user", &self.user)
+            .field("pass", &self.pass.as_ref().map(|_| "[REDACTED]"))
+            .field("auth_token", &self.auth_token.as_ref().map(|_| "[REDACTED]"))
+            .field("nkey", &self.nkey)
+            .field("sig", &self.sig)
+            .field("jwt", &self.jwt.as_ref().map(|_| "[REDACTED]"))
+            .finish()
+    }
 }
 
 impl ClientRequest {
@@ -111,11 +191,26 @@ impl ClientRequest {
         self.msg_buffer.clear();
         self.args.clear();
         self.msg_size = 0;
+        self.hdr_size = None;
     }
 
     fn parse_error(&mut self) -> Result<ClientCommand, ParseError> {
+        self.limit_error(InvalidInput)
+    }
+
+    fn limit_error(&mut self, e: ParseError) -> Result<ClientCommand, ParseError> {
         self.reset_state();
-        Err(InvalidInput)
+        Err(e)
+    }
+
+    // arguments are buffered until the end of line, so the buffer is capped to protect the server
+    fn push_arg(&mut self, c: char) -> Result<(), ParseError> {
+        if self.arg_buffer.len() >= self.max_control_line {
+            error!("control line exceeds maximum of {}", self.max_control_line);
+            return Err(MaxControlLineExceeded);
+        }
+        self.arg_buffer.push(c);
+        Ok(())
     }
 
     fn return_command(&mut self, command: ClientCommand) -> Result<ClientCommand, ParseError> {
@@ -130,6 +225,7 @@ impl ClientRequest {
                 OpStart => {
                     match c {
                         'C' | 'c' => self.parser_state = OpC,
+                        'H' | 'h' => self.parser_state = OpH,
                         'P' | 'p' => self.parser_state = OpP,
                         'S' | 's' => self.parser_state = OpS,
                         'U' | 'u' => self.parser_state = OpU,
@@ -198,7 +294,9 @@ impl ClientRequest {
                         }
                         '\r' => {} // ignore
                         _ => {
-                            self.arg_buffer.push(c);
+                            if let Err(e) = self.push_arg(c) {
+                                return (self.limit_error(e), i);
+                            }
                         }
                     }
                 }
@@ -266,11 +364,16 @@ impl ClientRequest {
                         '\n' => {
                             let args = split_arg(&self.arg_buffer);
 
-                            if args.len() != 2 {
+                            // PUB <subject> [reply-to] <size>
+                            if args.len() != 2 && args.len() != 3 {
                                 return (self.parse_error(), i);
                             }
 
-                            match parse_uint(&args[1]) {
+                            match parse_uint(&args[args.len() - 1]) {
+                                Ok(size) if size as usize > self.max_payload => {
+                                    error!("payload size {} exceeds maximum of {}", size, self.max_payload);
+                                    return (self.limit_error(MaxPayloadExceeded), i);
+                                }
                                 Ok(size) => {
                                     self.args = args;
                                     self.msg_size = size as usize;
@@ -284,34 +387,103 @@ impl ClientRequest {
                         }
                         '\r' => {} // ignore
                         _ => {
-                            self.arg_buffer.push(c);
+                            if let Err(e) = self.push_arg(c) {
+                                return (self.limit_error(e), i);
+                            }
                         }
                     }
                 }
                 PubMsg => {
+                    // payloads are binary, only look for the terminator once msg_size bytes are read
+                    if self.msg_buffer.len() < self.msg_size {
+                        self.msg_buffer.push(*b);
+                        continue;
+                    }
                     match c {
                         '\r'|'\n' => {
-                            if  self.msg_buffer.len() != self.msg_size {
-                                error!("message size mismatch. counter = {}, msg size = {}", self.msg_buffer.len(), self.msg_size);
+                            let arg: String = self.args[0].iter().collect();
+                            // HPUB carries one more size argument than PUB
+                            let reply_to_args_len = if self.hdr_size.is_some() { 4 } else { 3 };
+                            let reply_to: Option<String> = if self.args.len() == reply_to_args_len {
+                                Some(self.args[1].iter().collect())
+                            } else {
+                                None
+                            };
+                            let mut msg = Bytes::from(std::mem::take(&mut self.msg_buffer));
+                            let headers = self.hdr_size.map(|hdr_size| msg.split_to(hdr_size));
+                            if headers.as_ref().is_some_and(|headers| !headers.starts_with(HEADER_VERSION)) {
+                                error!("invalid headers for subject {}", arg);
                                 return (self.parse_error(), i);
                             }
-                            let arg: String = self.args[0].iter().collect();
-                            return match from_utf8(&self.msg_buffer) {
-                                Ok(msg) => {
-                                    (self.return_command(Pub { subject: arg, msg: msg.to_string() }), i)
+                            return (self.return_command(Pub { subject: arg, reply_to, headers, msg }), i);
+                        }
+                        _ => {
+                            error!("message size mismatch. msg size = {}", self.msg_size);
+                            return (self.parse_error(), i);
+                        }
+                    }
+                }
+
+                OpH => {
+                    match c {
+                        'P' | 'p' => self.parser_state = OpHp,
+                        _ => return (self.parse_error(), i),
+                    }
+                }
+                OpHp => {
+                    match c {
+                        'U' | 'u' => self.parser_state = OpHpu,
+                        _ => return (self.parse_error(), i),
+                    }
+                }
+                OpHpu => {
+                    match c {
+                        'B' | 'b' => self.parser_state = OpHpub,
+                        _ => return (self.parse_error(), i),
+                    }
+                }
+                OpHpub => {
+                    match c {
+                        ' ' | '\t' => self.parser_state = HpubArg,
+                        _ => return (self.parse_error(), i),
+                    }
+                }
+                HpubArg => {
+                    match c {
+                        '\n' => {
+                            let args = split_arg(&self.arg_buffer);
+
+                            // HPUB <subject> [reply-to] <header size> <total size>
+                            if args.len() != 3 && args.len() != 4 {
+                                return (self.parse_error(), i);
+                            }
+
+                            match (parse_uint(&args[args.len() - 2]), parse_uint(&args[args.len() - 1])) {
+                                (Ok(_), Ok(size)) if size as usize > self.max_payload => {
+                                    error!("payload size {} exceeds maximum of {}", size, self.max_payload);
+                                    return (self.limit_error(MaxPayloadExceeded), i);
                                 }
-                                Err(e) => {
-                                    error!("error parsing utf8 PUB message for subject {}: {}", arg, e);
-                                    (self.parse_error(), i)
+                                (Ok(hdr_size), Ok(size)) if hdr_size <= size => {
+                                    self.args = args;
+                                    self.hdr_size = Some(hdr_size as usize);
+                                    self.msg_size = size as usize;
+                                    self.parser_state = PubMsg;
                                 }
-                            };
+                                (Ok(hdr_size), Ok(size)) => {
+                                    error!("header size {} is larger than total size {}", hdr_size, size);
+                                    return (self.parse_error(), i);
+                                }
+                                (Err(e), _) | (_, Err(e)) => {
+                                    error!("error parsing number: {}", e);
+                                    return (self.parse_error(), i);
+                                }
+                            }
                         }
+                        '\r' => {} // ignore
                         _ => {
-                            if self.msg_buffer.len() > self.msg_size {
-                                error!("message size mismatch. counter = {}, msg size = {}", self.msg_buffer.len(), self.msg_size);
-                                return (self.parse_error(), i);
+                            if let Err(e) = self.push_arg(c) {
+                                return (self.limit_error(e), i);
                             }
-                            self.msg_buffer.push(*b);
                         }
                     }
                 }
@@ -338,18 +510,23 @@ impl ClientRequest {
                     match c {
                         '\n' => {
                             let args = split_arg(&self.arg_buffer);
-                            if args.len() != 2 {
-                                return (self.parse_error(), i);
-                            }
+                            let (subject, queue_group, id) = match args.len() {
+                                2 => (&args[0], None, &args[1]),
+                                3 => (&args[0], Some(args[1].iter().collect()), &args[2]),
+                                _ => return (self.parse_error(), i),
+                            };
 
                             return (self.return_command(Sub {
-                                subject: args[0].iter().collect(),
-                                id: args[1].iter().collect(),
+                                subject: subject.iter().collect(),
+                                queue_group,
+                                id: id.iter().collect(),
                             }), i);
                         }
                         '\r' => {} // ignore
                         _ => {
-                            self.arg_buffer.push(c);
+                            if let Err(e) = self.push_arg(c) {
+                                return (self.limit_error(e), i);
+                            }
                         }
                     }
                 }
@@ -386,13 +563,30 @@ impl ClientRequest {
                 UnsubArg => {
                     match c {
                         '\n' => {
+                            // UNSUB <sid> [max_msgs]
+                            let args = split_arg(&self.arg_buffer);
+                            let max_msgs = match args.len() {
+                                1 => None,
+                                2 => match parse_uint(&args[1]) {
+                                    Ok(max_msgs) => Some(max_msgs),
+                                    Err(e) => {
+                                        error!("error parsing number: {}", e);
+                                        return (self.parse_error(), i);
+                                    }
+                                },
+                                _ => return (self.parse_error(), i),
+                            };
+
                             return (self.return_command(Unsub {
-                                id: self.arg_buffer.iter().collect(),
+                                id: args[0].iter().collect(),
+                                max_msgs,
                             }), i);
                         }
                         '\r' => {} // ignore
                         _ => {
-                            self.arg_buffer.push(c);
+                            if let Err(e) = self.push_arg(c) {
+                                return (self.limit_error(e), i);
+                            }
                         }
                     }
                 }
@@ -403,13 +597,16 @@ impl ClientRequest {
         (Ok(Noop), buf.len())
     }
 
-    pub fn new() -> Self {
+    pub fn new(max_payload: usize, max_control_line: usize) -> Self {
         Self {
             parser_state: ParserState::OpStart,
             arg_buffer: vec![],
             msg_buffer: vec![],
             msg_size: 0,
+            hdr_size: None,
             args: vec![vec![]],
+            max_payload,
+            max_control_line,
         }
     }
 }
@@ -431,12 +628,15 @@ mod test {
     #[test_case("PUB subject 3", PubArg; "pub arg with msg len")]
     #[test_case("PUB subject 3\r\n", PubMsg; "pub arg with msg len before message")]
     #[test_case("PUB subject 3\r\nyes", PubMsg; "pub arg with msg len and message")]
+    #[test_case("PUB subj 300\r\nyeah\r\n", PubMsg; "pub message shorter than msg len")]
+    #[test_case("HPUB subject 12 17", HpubArg; "hpub arg")]
+    #[test_case("HPUB subject 12 17\r\nNATS", PubMsg; "hpub arg with headers")]
     #[test_case("SUB subject", SubArg; "sub arg")]
     #[test_case("SUB subject id", SubArg; "sub arg with id")]
     #[test_case("UNSUB subject", UnsubArg; "unsub arg with id")]
     fn test_parse_state_ok(input: &str, expected: ParserState) {
         init();
-        let mut client = ClientRequest::new();
+        let mut client = ClientRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
         let _ = client.parse(input.as_bytes());
         assert_eq!(expected, client.parser_state);
     }
@@ -448,36 +648,61 @@ mod test {
     #[test_case("CONNECT {yeah}\r\n", InvalidInput; "connect invalid arg")]
     #[test_case("PUB\r\n", InvalidInput; "pub without arg")]
     #[test_case("PUB s\r\n", InvalidInput; "pub not enough arg")]
+    #[test_case("PUB s r x 5\r\nhello\r\n", InvalidInput; "pub too many arg")]
     #[test_case("PUB subj -3\r\nyes\r\n", InvalidInput; "pub message invalid negative size")]
     #[test_case("PUB subj x\r\nyes\r\n", InvalidInput; "pub message invalid size not a number")]
     #[test_case("PUB subj 3\r\ntoolong\r\n", InvalidInput; "pub message too long")]
-    #[test_case("PUB subj 300\r\nyeah\r\n", InvalidInput; "pub message too short")]
+    #[test_case("HPUB subj 5\r\nhello\r\n", InvalidInput; "hpub not enough arg")]
+    #[test_case("HPUB subj 20 10\r\nhello\r\n", InvalidInput; "hpub header larger than total")]
+    #[test_case("HPUB subj 5 10\r\nhello12345\r\n", InvalidInput; "hpub invalid header version")]
     #[test_case("SUB\r\n", InvalidInput; "sub without arg")]
     #[test_case("SUB s\r\n", InvalidInput; "sub not enough arg")]
+    #[test_case("SUB s q id extra\r\n", InvalidInput; "sub too many arg")]
     #[test_case("UNSUB\r\n", InvalidInput; "unsub without arg")]
+    #[test_case("UNSUB id x\r\n", InvalidInput; "unsub max msgs not a number")]
+    #[test_case("UNSUB id 5 extra\r\n", InvalidInput; "unsub too many arg")]
     fn test_parse_fail(input: &str, expected: ParseError) {
         init();
-        let mut client = ClientRequest::new();
+        let mut client = ClientRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
         let actual = client.parse(input.as_bytes()).0.unwrap_err();
         assert_eq!(expected, actual);
     }
 
-    #[test_case("CONNECT {}\r\n", Connect(ClientConnectOpts{verbose: false}); "connect command")]
-    #[test_case("CONNECT\t{\"verbose\": true}\r\n", Connect(ClientConnectOpts{verbose: true}); "connect with tab and argument")]
+    #[test_case("CONNECT {}\r\n", Connect(ClientConnectOpts::default()); "connect command")]
+    #[test_case("CONNECT\t{\"verbose\": true}\r\n", Connect(ClientConnectOpts{verbose: true, ..Default::default()}); "connect with tab and argument")]
+    #[test_case("CONNECT {\"headers\": true}\r\n", Connect(ClientConnectOpts{headers: true, ..Default::default()}); "connect with headers")]
+    #[test_case("CONNECT {\"echo\": false, \"pedantic\": true, \"no_responders\": true, \"protocol\": 1}\r\n", Connect(ClientConnectOpts{echo: false, pedantic: true, no_responders: true, protocol: 1, ..Default::default()}); "connect with flags")]
+    #[test_case("CONNECT {\"name\": \"app\", \"lang\": \"go\", \"version\": \"1.2.3\", \"user\": \"u\", \"pass\": \"p\", \"auth_token\": \"t\"}\r\n", Connect(ClientConnectOpts{name: Some("app".to_string()), lang: Some("go".to_string()), version: Some("1.2.3".to_string()), user: Some("u".to_string()), pass: Some("p".to_string()), auth_token: Some("t".to_string()), ..Default::default()}); "connect with client info and credentials")]
+    #[test_case("CONNECT {\"nkey\": \"UABC\", \"sig\": \"c2ln\"}\r\n", Connect(ClientConnectOpts{nkey: Some("UABC".to_string()), sig: Some("c2ln".to_string()), ..Default::default()}); "connect with nkey")]
+    #[test_case("CONNECT {\"jwt\": \"a.b.c\", \"sig\": \"c2ln\"}\r\n", Connect(ClientConnectOpts{jwt: Some("a.b.c".to_string()), sig: Some("c2ln".to_string()), ..Default::default()}); "connect with jwt")]
+    #[test_case("CONNECT {\"verbose\": false, \"tls_required\": false, \"unknown\": 1}\r\n", Connect(ClientConnectOpts::default()); "connect ignores unknown options")]
     #[test_case("PING\r\n", Ping; "ping command")]
     #[test_case("PONG\r\n", Pong; "pong command")]
-    #[test_case("SUB subject id\r\n", Sub{subject: "subject".to_string(), id: "id".to_string()}; "sub command")]
-    #[test_case("SUB\tsubject\tid\r\n", Sub{subject: "subject".to_string(), id: "id".to_string()}; "sub command with tab")]
-    #[test_case("PUB subject 5\r\nhello\r\n", Pub{subject: "subject".to_string(), msg: "hello".to_string()}; "pub command")]
-    #[test_case("PUB\tsubject\t5\r\nhello\r\n", Pub{subject: "subject".to_string(), msg: "hello".to_string()}; "pub command with tab")]
-    #[test_case("PUB subject 0\r\n\r\n", Pub{subject: "subject".to_string(), msg: "".to_string()}; "pub command empty message")]
-    #[test_case("UNSUB id\r\n", Unsub{id: "id".to_string()}; "unsub command")]
+    #[test_case("SUB subject id\r\n", Sub{subject: "subject".to_string(), queue_group: None, id: "id".to_string()}; "sub command")]
+    #[test_case("SUB\tsubject\tid\r\n", Sub{subject: "subject".to_string(), queue_group: None, id: "id".to_string()}; "sub command with tab")]
+    #[test_case("SUB subject workers id\r\n", Sub{subject: "subject".to_string(), queue_group: Some("workers".to_string()), id: "id".to_string()}; "sub command with queue group")]
+    #[test_case("PUB subject 5\r\nhello\r\n", Pub{subject: "subject".to_string(), reply_to: None, headers: None, msg: Bytes::from("hello")}; "pub command")]
+    #[test_case("PUB\tsubject\t5\r\nhello\r\n", Pub{subject: "subject".to_string(), reply_to: None, headers: None, msg: Bytes::from("hello")}; "pub command with tab")]
+    #[test_case("PUB subject 0\r\n\r\n", Pub{subject: "subject".to_string(), reply_to: None, headers: None, msg: Bytes::from("")}; "pub command empty message")]
+    #[test_case("PUB subject reply 5\r\nhello\r\n", Pub{subject: "subject".to_string(), reply_to: Some("reply".to_string()), headers: None, msg: Bytes::from("hello")}; "pub command with reply")]
+    #[test_case("PUB subject 7\r\nhe\r\nllo\r\n", Pub{subject: "subject".to_string(), reply_to: None, headers: None, msg: Bytes::from("he\r\nllo")}; "pub command with line break in message")]
+    #[test_case("HPUB subject 12 17\r\nNATS/1.0\r\n\r\nhello\r\n", Pub{subject: "subject".to_string(), reply_to: None, headers: Some(Bytes::from("NATS/1.0\r\n\r\n")), msg: Bytes::from("hello")}; "hpub command")]
+    #[test_case("HPUB subject reply 12 12\r\nNATS/1.0\r\n\r\n\r\n", Pub{subject: "subject".to_string(), reply_to: Some("reply".to_string()), headers: Some(Bytes::from("NATS/1.0\r\n\r\n")), msg: Bytes::new()}; "hpub command with reply and empty message")]
+    #[test_case("UNSUB id\r\n", Unsub{id: "id".to_string(), max_msgs: None}; "unsub command")]
+    #[test_case("UNSUB id 5\r\n", Unsub{id: "id".to_string(), max_msgs: Some(5)}; "unsub command with max msgs")]
     fn test_parse_ok(input: &str, expected: ClientCommand) {
-        let mut client = ClientRequest::new();
+        let mut client = ClientRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
         let actual = client.parse(input.as_bytes()).0.unwrap();
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_parse_binary_message() {
+        let mut client = ClientRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
+        let actual = client.parse(b"PUB subject 3\r\n\xff\x00\xfe\r\n").0.unwrap();
+        assert_eq!(Pub{subject: "subject".to_string(), reply_to: None, headers: None, msg: Bytes::from_static(&[0xff, 0x00, 0xfe])}, actual);
+    }
+
 
     #[test_case(vec!['s','u','p'], vec![vec!['s','u','p']]; "one arg")]
     #[test_case(vec!['s','u','p',' ',' '], vec![vec!['s','u','p']]; "one arg extra space")]
@@ -495,17 +720,29 @@ mod test {
     #[test_case(vec!['-','3','6','1'], Err(NotAPositiveInt); "negative number")]
     #[test_case(vec!['3','.','1'], Err(NotAPositiveInt); "floating number")]
     #[test_case(vec!['a','3','1'], Err(NotAPositiveInt); "not a number")]
+    #[test_case(vec!['9','9','9','9','9','9','9','9','9','9','9'], Err(NotAPositiveInt); "overflow")]
     fn test_parse_uint(input: Vec<char>, expected_output: Result<u32, ParseError>) {
         init();
         let actual = parse_uint(&input);
         assert_eq!(expected_output, actual);
     }
 
+    #[test_case("PUB subject 11\r\nhello world\r\n", MaxPayloadExceeded; "pub payload too large")]
+    #[test_case("HPUB subject 12 11\r\nhello world\r\n", MaxPayloadExceeded; "hpub payload too large")]
+    #[test_case("SUB subject.that.is.long id\r\n", MaxControlLineExceeded; "sub control line too long")]
+    #[test_case("CONNECT {\"verbose\": false}\r\n", MaxControlLineExceeded; "connect control line too long")]
+    fn test_parse_limits(input: &str, expected: ParseError) {
+        init();
+        let mut client = ClientRequest::new(10, 16);
+        let actual = client.parse(input.as_bytes()).0.unwrap_err();
+        assert_eq!(expected, actual);
+    }
+
     #[test_case("PIN\r\nPING", 3; "invalid ping")]
     #[test_case("PING\r\n", 5; "correct ping")]
     #[test_case("PING\r\nPING\r\n", 5; "correct ping extra ignored")]
     fn test_parse_return_bytes_read(input: &str, expected: usize) {
-        let mut client = ClientRequest::new();
+        let mut client = ClientRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
         let actual = client.parse(input.as_bytes()).1;
         assert_eq!(expected, actual);
     }
@@ -0,0 +1,804 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Duration, Instant};
+use crate::commands::MainCommand;
+use crate::config::LinkAuthorization;
+use crate::handlers::Keepalive;
+use crate::parser::ParseError;
+use crate::parser::ParseError::{InvalidInput, MaxControlLineExceeded, MaxPayloadExceeded};
+use crate::server::Server;
+
+// delay before a configured route is connected again
+const ROUTE_CONNECT_RETRY: Duration = Duration::from_secs(2);
+
+// a connected server of the cluster, commands sent to it are queued without limit so the
+// main loop never waits on a slow route and interest updates stay in order
+#[derive(Debug)]
+pub struct Route {
+    pub route_id: u32,
+    pub info: RouteInfo,
+    pub tx: UnboundedSender<RouteCommand>,
+}
+
+// routes and leaf nodes share the protocol, leaf node commands have an `L` prefix and leave out
+// the account as each leaf node connection is bound to a single account
+#[derive(Debug, Clone)]
+pub enum Link {
+    // solicited routes were opened by this server
+    Route { solicited: bool },
+    // solicited leaf nodes connect to a remote hub with its credentials
+    Leafnode { account: String, solicited: bool, credentials: Option<LinkAuthorization> },
+}
+
+// local subscribers of a server in an account, sent to the other servers with RS+ and RS-
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Interest {
+    pub account: String,
+    pub subject: String,
+    pub queue_group: Option<String>,
+}
+
+// credentials sent before INFO when the other side requires them
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RouteConnectOpts {
+    pub user: String,
+    pub pass: String,
+}
+
+// INFO exchanged by both sides as soon as a route connects and again whenever a server joins the cluster
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RouteInfo {
+    pub server_id: String,
+    pub server_name: String,
+    pub version: String,
+    // address of the cluster listener of the server
+    pub route_url: String,
+    // address clients connect to, passed on to clients as a connect url
+    pub client_url: String,
+    // route addresses of the servers it is connected to, so the full mesh forms from a single route
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<String>,
+    // server ids of the cluster of a leaf node hub, a leaf node connects to one server of a cluster only
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cluster: Vec<String>,
+}
+
+impl RouteInfo {
+    // servers listening on all interfaces without `advertise` send addresses like 0.0.0.0:6222, the
+    // address the route connected from is used instead
+    fn resolve_unspecified(mut self, ip: IpAddr) -> Self {
+        for url in [&mut self.route_url, &mut self.client_url] {
+            if let Some(addr) = unspecified_addr(url) {
+                *url = SocketAddr::new(ip, addr.port()).to_string();
+            }
+        }
+        self
+    }
+}
+
+pub fn unspecified_addr(url: &str) -> Option<SocketAddr> {
+    url.parse::<SocketAddr>().ok().filter(|addr| addr.ip().is_unspecified())
+}
+
+// message published on another server, queue groups not served there are listed
+// so only one server delivers to each of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutedMessage {
+    pub account: String,
+    pub subject: String,
+    pub reply_to: Option<String>,
+    pub headers: Option<Bytes>,
+    pub msg: Bytes,
+    pub queue_groups: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RouteCommand {
+    Noop,
+    Connect(RouteConnectOpts),
+    Info(RouteInfo),
+    Subscribe(Interest),
+    Unsubscribe(Interest),
+    Msg(RoutedMessage),
+    Ping,
+    Pong,
+}
+
+impl RouteCommand {
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(false)
+    }
+
+    pub fn encode_leafnode(&self) -> Vec<u8> {
+        self.encode_with(true)
+    }
+
+    fn encode_with(&self, leafnode: bool) -> Vec<u8> {
+        let prefix = if leafnode { "L" } else { "R" };
+        let account = |account: &str| if leafnode { String::new() } else { format!(" {}", account) };
+        let mut buf = vec![];
+        match self {
+            RouteCommand::Noop => {}
+            RouteCommand::Connect(opts) => {
+                let opts = serde_json::to_string(opts).unwrap_or_default();
+                buf.extend_from_slice(format!("CONNECT {}\r\n", opts).as_bytes());
+            }
+            RouteCommand::Info(info) => {
+                let info = serde_json::to_string(info).unwrap_or_default();
+                buf.extend_from_slice(format!("INFO {}\r\n", info).as_bytes());
+            }
+            // the weight of queue subscriptions is always 1
+            RouteCommand::Subscribe(interest) => match &interest.queue_group {
+                Some(queue_group) => buf.extend_from_slice(format!("{}S+{} {} {} 1\r\n", prefix, account(&interest.account), interest.subject, queue_group).as_bytes()),
+                None => buf.extend_from_slice(format!("{}S+{} {}\r\n", prefix, account(&interest.account), interest.subject).as_bytes()),
+            },
+            RouteCommand::Unsubscribe(interest) => match &interest.queue_group {
+                Some(queue_group) => buf.extend_from_slice(format!("{}S-{} {} {}\r\n", prefix, account(&interest.account), interest.subject, queue_group).as_bytes()),
+                None => buf.extend_from_slice(format!("{}S-{} {}\r\n", prefix, account(&interest.account), interest.subject).as_bytes()),
+            },
+            RouteCommand::Msg(message) => {
+                let op = if message.headers.is_some() { "H".to_string() } else { prefix.to_string() };
+                let mut line = format!("{}MSG{} {}", op, account(&message.account), message.subject);
+                // with queue groups the reply is marked with `+`, or replaced by `|` when there is none
+                match (&message.reply_to, message.queue_groups.is_empty()) {
+                    (Some(reply_to), true) => line.push_str(&format!(" {}", reply_to)),
+                    (Some(reply_to), false) => line.push_str(&format!(" + {}", reply_to)),
+                    (None, false) => line.push_str(" |"),
+                    (None, true) => {}
+                }
+                for queue_group in &message.queue_groups {
+                    line.push(' ');
+                    line.push_str(queue_group);
+                }
+                match &message.headers {
+                    Some(headers) => line.push_str(&format!(" {} {}\r\n", headers.len(), headers.len() + message.msg.len())),
+                    None => line.push_str(&format!(" {}\r\n", message.msg.len())),
+                }
+                buf.extend_from_slice(line.as_bytes());
+                if let Some(headers) = &message.headers {
+                    buf.extend_from_slice(headers);
+                }
+                buf.extend_from_slice(&message.msg);
+                buf.extend_from_slice(b"\r\n");
+            }
+            RouteCommand::Ping => buf.extend_from_slice(b"PING\r\n"),
+            RouteCommand::Pong => buf.extend_from_slice(b"PONG\r\n"),
+        }
+        buf
+    }
+}
+
+// RMSG or HMSG waiting for its payload
+struct PendingMessage {
+    account: String,
+    subject: String,
+    reply_to: Option<String>,
+    queue_groups: Vec<String>,
+    hdr_size: Option<usize>,
+    size: usize,
+}
+
+// routes are trusted servers, so unlike the client parser the control line is buffered and
+// parsed as a whole
+pub struct RouteRequest {
+    // the account of a leaf node connection, routes send it with every command
+    leafnode_account: Option<String>,
+    line: Vec<u8>,
+    pending: Option<PendingMessage>,
+    payload: BytesMut,
+    max_payload: usize,
+    max_control_line: usize,
+}
+
+impl RouteRequest {
+    pub fn new(max_payload: usize, max_control_line: usize) -> Self {
+        Self {
+            leafnode_account: None,
+            line: vec![],
+            pending: None,
+            payload: BytesMut::new(),
+            max_payload,
+            max_control_line,
+        }
+    }
+
+    pub fn leafnode(max_payload: usize, max_control_line: usize, account: String) -> Self {
+        Self {
+            leafnode_account: Some(account),
+            ..Self::new(max_payload, max_control_line)
+        }
+    }
+
+    fn error(&mut self, e: ParseError) -> Result<RouteCommand, ParseError> {
+        self.line.clear();
+        self.pending = None;
+        self.payload.clear();
+        Err(e)
+    }
+
+    // same contract as the client parser, returns the command and the index of the last byte read
+    pub fn parse(&mut self, buf: &[u8]) -> (Result<RouteCommand, ParseError>, usize) {
+        for (i, b) in buf.iter().enumerate() {
+            if let Some(pending) = &self.pending {
+                if self.payload.len() < pending.size {
+                    self.payload.extend_from_slice(&[*b]);
+                    continue;
+                }
+                return match b {
+                    b'\r' | b'\n' => (Ok(self.finish_message()), i),
+                    _ => {
+                        error!("routed message size mismatch. msg size = {}", pending.size);
+                        (self.error(InvalidInput), i)
+                    }
+                };
+            }
+            match b {
+                b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    return match self.parse_line(&line) {
+                        Ok(command) => (Ok(command), i),
+                        Err(e) => (self.error(e), i),
+                    };
+                }
+                b'\r' => {}
+                _ => {
+                    if self.line.len() >= self.max_control_line {
+                        error!("route control line exceeds maximum of {}", self.max_control_line);
+                        return (self.error(MaxControlLineExceeded), i);
+                    }
+                    self.line.push(*b);
+                }
+            }
+        }
+        (Ok(RouteCommand::Noop), buf.len())
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<RouteCommand, ParseError> {
+        let line = std::str::from_utf8(line).map_err(|_| InvalidInput)?;
+        let (op, arg) = line.split_once([' ', '\t']).unwrap_or((line, ""));
+        let mut args: Vec<&str> = arg.split_whitespace().collect();
+        let mut op = op.to_ascii_uppercase();
+        // leaf node commands are read as route commands of the account the connection is bound to
+        let leafnode_account = self.leafnode_account.clone();
+        if let Some(account) = &leafnode_account {
+            match op.as_str() {
+                "LS+" | "LS-" | "LMSG" | "HMSG" => {
+                    args.insert(0, account);
+                    op = op.replacen('L', "R", 1);
+                }
+                "RS+" | "RS-" | "RMSG" => return Err(InvalidInput),
+                _ => {}
+            }
+        }
+        match op.as_str() {
+            "" => Ok(RouteCommand::Noop),
+            "PING" if args.is_empty() => Ok(RouteCommand::Ping),
+            "PONG" if args.is_empty() => Ok(RouteCommand::Pong),
+            "CONNECT" => serde_json::from_str(arg).map(RouteCommand::Connect).map_err(|e| {
+                error!("error parsing route connect: {}", e);
+                InvalidInput
+            }),
+            "INFO" => serde_json::from_str(arg).map(RouteCommand::Info).map_err(|e| {
+                error!("error parsing route info: {}", e);
+                InvalidInput
+            }),
+            // RS+ <account> <subject> [<queue> <weight>]
+            "RS+" => match args[..] {
+                [account, subject] => Ok(RouteCommand::Subscribe(interest(account, subject, None))),
+                [account, subject, queue_group] | [account, subject, queue_group, _] => Ok(RouteCommand::Subscribe(interest(account, subject, Some(queue_group)))),
+                _ => Err(InvalidInput),
+            },
+            // RS- <account> <subject> [<queue>]
+            "RS-" => match args[..] {
+                [account, subject] => Ok(RouteCommand::Unsubscribe(interest(account, subject, None))),
+                [account, subject, queue_group] => Ok(RouteCommand::Unsubscribe(interest(account, subject, Some(queue_group)))),
+                _ => Err(InvalidInput),
+            },
+            "RMSG" => self.start_message(&args, false),
+            "HMSG" => self.start_message(&args, true),
+            _ => Err(InvalidInput),
+        }
+    }
+
+    // RMSG <account> <subject> [[+ <reply> | <reply> | |] <queue>...] [<header size>] <size>
+    fn start_message(&mut self, args: &[&str], headers: bool) -> Result<RouteCommand, ParseError> {
+        let sizes = if headers { 2 } else { 1 };
+        if args.len() < 2 + sizes {
+            return Err(InvalidInput);
+        }
+        let (reply_to, queue_groups) = match &args[2..args.len() - sizes] {
+            [] => (None, &[][..]),
+            ["+", reply_to, queue_groups @ ..] => (Some(reply_to.to_string()), queue_groups),
+            ["|", queue_groups @ ..] => (None, queue_groups),
+            [reply_to] => (Some(reply_to.to_string()), &[][..]),
+            _ => return Err(InvalidInput),
+        };
+        let size: usize = args[args.len() - 1].parse().map_err(|_| InvalidInput)?;
+        let hdr_size: Option<usize> = match headers {
+            true => Some(args[args.len() - 2].parse().map_err(|_| InvalidInput)?),
+            false => None,
+        };
+        if size > self.max_payload {
+            error!("routed payload size {} exceeds maximum of {}", size, self.max_payload);
+            return Err(MaxPayloadExceeded);
+        }
+        if hdr_size.is_some_and(|hdr_size| hdr_size > size) {
+            return Err(InvalidInput);
+        }
+        self.pending = Some(PendingMessage {
+            account: args[0].to_string(),
+            subject: args[1].to_string(),
+            reply_to,
+            queue_groups: queue_groups.iter().map(|queue_group| queue_group.to_string()).collect(),
+            hdr_size,
+            size,
+        });
+        Ok(RouteCommand::Noop)
+    }
+
+    fn finish_message(&mut self) -> RouteCommand {
+        let Some(pending) = self.pending.take() else {
+            return RouteCommand::Noop;
+        };
+        let mut msg = std::mem::take(&mut self.payload).freeze();
+        let headers = pending.hdr_size.map(|hdr_size| msg.split_to(hdr_size));
+        RouteCommand::Msg(RoutedMessage {
+            account: pending.account,
+            subject: pending.subject,
+            reply_to: pending.reply_to,
+            headers,
+            msg,
+            queue_groups: pending.queue_groups,
+        })
+    }
+}
+
+fn interest(account: &str, subject: &str, queue_group: Option<&str>) -> Interest {
+    Interest {
+        account: account.to_string(),
+        subject: subject.to_string(),
+        queue_group: queue_group.map(str::to_string),
+    }
+}
+
+impl Server {
+    pub async fn route_info(&self) -> RouteInfo {
+        let routes = self.routes.read().await;
+        RouteInfo {
+            server_id: self.server_id.clone(),
+            server_name: self.config.server_name.clone().unwrap_or_else(|| self.server_id.clone()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            route_url: self.route_url().unwrap_or_default(),
+            client_url: self.client_url().unwrap_or_default(),
+            routes: routes.values()
+                .map(|route| route.info.route_url.clone())
+                .filter(|route_url| !route_url.is_empty())
+                .collect(),
+            cluster: vec![],
+        }
+    }
+
+    pub fn route_url(&self) -> Option<String> {
+        self.config.cluster.as_ref().map(|cluster| cluster.advertise.clone().unwrap_or_else(|| cluster.listener.clone()))
+    }
+
+    // may be an address on all interfaces, the other servers replace it with the address this
+    // server connected from
+    pub fn client_url(&self) -> Option<String> {
+        self.config.client_advertise.clone()
+            .or_else(|| self.config.listeners.first().map(|listener| listener.address.clone()))
+    }
+
+    // solicits the routes this server is not connected to yet, configured routes are retried
+    // forever while discovered routes are forgotten once they fail
+    pub async fn connect_routes(self: &Arc<Self>, urls: Vec<String>, discovered: bool) {
+        let mut solicited_routes = self.solicited_routes.write().await;
+        let routes = self.routes.read().await;
+        let route_url = self.route_url();
+        for url in urls {
+            if route_url.as_ref() == Some(&url) || routes.values().any(|route| route.info.route_url == url) {
+                continue;
+            }
+            if !solicited_routes.insert(url.clone()) {
+                continue;
+            }
+            if discovered {
+                info!("discovered route {}", url);
+            }
+            let server = self.clone();
+            tokio::spawn(async move {
+                server.solicit_route(url, discovered).await;
+            });
+        }
+    }
+
+    // keeps a route connected, the other server may already be connected through its own route
+    // to this server, in which case this one waits until that route is gone
+    async fn solicit_route(&self, url: String, discovered: bool) {
+        loop {
+            match TcpStream::connect(&url).await {
+                Ok(socket) => {
+                    if let Some(server_id) = self.handle_route(socket, true).await {
+                        // the other servers only know this server by the address its routes connect from
+                        if server_id == self.server_id && discovered {
+                            debug!("discovered route {} is this server", url);
+                            return;
+                        }
+                        if server_id == self.server_id {
+                            warn!("route {} points to this server", url);
+                            return;
+                        }
+                        while !discovered && self.routes.read().await.contains_key(&server_id) {
+                            sleep(ROUTE_CONNECT_RETRY).await;
+                        }
+                    }
+                }
+                Err(e) => debug!("unable to connect route {}: {}", url, e),
+            }
+            if discovered {
+                self.solicited_routes.write().await.remove(&url);
+                return;
+            }
+            sleep(ROUTE_CONNECT_RETRY).await;
+        }
+    }
+
+    pub async fn handle_route(&self, socket: TcpStream, solicited: bool) -> Option<String> {
+        self.handle_link(socket, Link::Route { solicited }).await
+    }
+
+    // both sides send INFO right away, interest and messages are only exchanged once the link is
+    // registered with the server id of the other side, which is returned when the link closes
+    pub async fn handle_link(&self, mut socket: TcpStream, link: Link) -> Option<String> {
+        let route_id = self.client_id.fetch_add(1, SeqCst);
+        let info = match &link {
+            Link::Route { .. } => self.route_info().await,
+            Link::Leafnode { .. } => self.leafnode_info().await,
+        };
+        // credentials go first, the other side registers the link as soon as it reads INFO
+        let mut handshake = vec![];
+        if let Some(credentials) = link.credentials(self) {
+            handshake.extend(link.encode(&RouteCommand::Connect(RouteConnectOpts { user: credentials.user, pass: credentials.password })));
+        }
+        handshake.extend(link.encode(&RouteCommand::Info(info)));
+        if let Err(e) = socket.write_all(&handshake).await {
+            error!("error writing to {} {}: {}", link, route_id, e);
+            return None;
+        }
+        let authorization = link.authorization(self);
+        let mut authenticated = authorization.is_none();
+        let peer_ip = socket.peer_addr().ok().map(|addr| addr.ip());
+
+        let mut req_buffer = [0; 4096];
+        let mut route_request = match &link {
+            Link::Route { .. } => RouteRequest::new(self.config.max_payload, self.config.max_control_line),
+            Link::Leafnode { account, .. } => RouteRequest::leafnode(self.config.max_payload, self.config.max_control_line, account.clone()),
+        };
+        let (tx, mut rx) = unbounded_channel::<RouteCommand>();
+        // handed over to the main loop once the link is registered
+        let mut tx = Some(tx);
+        let mut server_id: Option<String> = None;
+
+        let ping_period = Duration::from_secs(self.config.ping_interval.max(1));
+        let mut ping_interval = tokio::time::interval_at(Instant::now() + ping_period, ping_period);
+        let mut keepalive = Keepalive::new(self.config.ping_max);
+
+        'link: loop {
+            tokio::select! {
+                _ = ping_interval.tick() => {
+                    if !keepalive.ping() {
+                        info!("{} {} has {} outstanding pings", link, route_id, keepalive.pings_outstanding());
+                        break;
+                    }
+                    if let Err(e) = socket.write_all(&link.encode(&RouteCommand::Ping)).await {
+                        error!("error writing to {} {}: {}", link, route_id, e);
+                        break;
+                    }
+                }
+
+                socket_result = socket.read(&mut req_buffer) => {
+                    let n = match socket_result {
+                        Ok(0) => {
+                            debug!("{} {} closed", link, route_id);
+                            break;
+                        }
+                        Ok(n) => n,
+                        Err(e) => {
+                            error!("error reading from {} {}: {}", link, route_id, e);
+                            break;
+                        }
+                    };
+                    let mut start = 0;
+                    while start < n {
+                        let (parsed, bytes_read) = route_request.parse(&req_buffer[start..n]);
+                        start += bytes_read + 1;
+                        let command = match (parsed, peer_ip) {
+                            (Ok(RouteCommand::Info(info)), Some(peer_ip)) => RouteCommand::Info(info.resolve_unspecified(peer_ip)),
+                            (Ok(command), _) => command,
+                            (Err(e), _) => {
+                                error!("error parsing {} {} command: {}", link, route_id, e);
+                                break 'link;
+                            }
+                        };
+                        let main_command = match (command, &server_id) {
+                            (RouteCommand::Noop, _) => continue,
+                            (RouteCommand::Ping, _) => {
+                                if let Err(e) = socket.write_all(&link.encode(&RouteCommand::Pong)).await {
+                                    error!("error writing to {} {}: {}", link, route_id, e);
+                                    break 'link;
+                                }
+                                continue;
+                            }
+                            (RouteCommand::Pong, _) => {
+                                keepalive.pong();
+                                continue;
+                            }
+                            (RouteCommand::Connect(_), _) if authenticated => continue,
+                            (RouteCommand::Connect(opts), _) => {
+                                let Some(authorization) = authorization.clone() else {
+                                    break 'link;
+                                };
+                                // passwords may be bcrypt hashes
+                                let verified = tokio::task::spawn_blocking(move || authorization.verify(&opts.user, &opts.pass)).await;
+                                if !verified.unwrap_or(false) {
+                                    warn!("{} {} failed to authenticate", link, route_id);
+                                    break 'link;
+                                }
+                                authenticated = true;
+                                continue;
+                            }
+                            (RouteCommand::Info(_), None) if !authenticated => {
+                                warn!("{} {} sent INFO without valid credentials", link, route_id);
+                                break 'link;
+                            }
+                            (RouteCommand::Info(info), None) => {
+                                let Some(tx) = tx.take() else {
+                                    break 'link;
+                                };
+                                let (processed, link_processed) = oneshot::channel();
+                                server_id = Some(info.server_id.clone());
+                                let info = Box::new(info);
+                                let connect = match &link {
+                                    Link::Route { solicited } => MainCommand::RouteConnect { route_id, info, solicited: *solicited, tx, processed },
+                                    Link::Leafnode { account, solicited, .. } => MainCommand::LeafnodeConnect { leafnode_id: route_id, account: account.clone(), solicited: *solicited, info, tx, processed },
+                                };
+                                if let Err(e) = self.main_tx.send(connect).await {
+                                    error!("error sending to main channel: {}", e);
+                                }
+                                if !link_processed.await.unwrap_or(false) {
+                                    debug!("{} {} to {:?} is not needed", link, route_id, server_id);
+                                    return server_id;
+                                }
+                                info!("{} {} connected to server {:?}", link, route_id, server_id);
+                                continue;
+                            }
+                            (_, None) => {
+                                error!("{} {} sent a command before INFO", link, route_id);
+                                break 'link;
+                            }
+                            (command, Some(_)) => match link.main_command(route_id, command) {
+                                Some(main_command) => main_command,
+                                None => continue,
+                            },
+                        };
+                        if let Err(e) = self.main_tx.send(main_command).await {
+                            error!("error sending to main channel: {}", e);
+                        }
+                    }
+                }
+
+                command = rx.recv() => {
+                    let Some(command) = command else {
+                        // the main loop dropped the link, it was replaced or the server shuts down
+                        debug!("{} {} removed", link, route_id);
+                        return server_id;
+                    };
+                    if let Err(e) = socket.write_all(&link.encode(&command)).await {
+                        error!("error writing to {} {}: {}", link, route_id, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if server_id.is_some() {
+            let disconnect = match &link {
+                Link::Route { .. } => MainCommand::RouteDisconnect { route_id },
+                Link::Leafnode { .. } => MainCommand::LeafnodeDisconnect { leafnode_id: route_id },
+            };
+            if let Err(e) = self.main_tx.send(disconnect).await {
+                error!("error sending to main channel: {}", e);
+            }
+        }
+        server_id
+    }
+}
+
+impl Link {
+    // credentials presented to the other side
+    fn credentials(&self, server: &Server) -> Option<LinkAuthorization> {
+        match self {
+            Link::Route { .. } => server.config.cluster.as_ref().and_then(|cluster| cluster.authorization.clone()),
+            Link::Leafnode { credentials, .. } => credentials.clone(),
+        }
+    }
+
+    // credentials required from the other side
+    fn authorization(&self, server: &Server) -> Option<LinkAuthorization> {
+        match self {
+            Link::Route { .. } => server.config.cluster.as_ref().and_then(|cluster| cluster.authorization.clone()),
+            Link::Leafnode { solicited: true, .. } => None,
+            Link::Leafnode { solicited: false, .. } => server.config.leafnodes.as_ref().and_then(|leafnodes| leafnodes.authorization.clone()),
+        }
+    }
+
+    fn encode(&self, command: &RouteCommand) -> Vec<u8> {
+        match self {
+            Link::Route { .. } => command.encode(),
+            Link::Leafnode { .. } => command.encode_leafnode(),
+        }
+    }
+
+    // commands of a registered link for the main loop, leaf nodes take no part in the gossip
+    fn main_command(&self, id: u32, command: RouteCommand) -> Option<MainCommand> {
+        match (self, command) {
+            (Link::Route { .. }, RouteCommand::Info(info)) => Some(MainCommand::RouteInfo { route_id: id, info: Box::new(info) }),
+            (Link::Route { .. }, RouteCommand::Subscribe(interest)) => Some(MainCommand::RouteSubscribe { route_id: id, interest }),
+            (Link::Route { .. }, RouteCommand::Unsubscribe(interest)) => Some(MainCommand::RouteUnsubscribe { route_id: id, interest }),
+            (Link::Route { .. }, RouteCommand::Msg(message)) => Some(MainCommand::RoutePublish { route_id: id, message }),
+            (Link::Leafnode { .. }, RouteCommand::Subscribe(interest)) => Some(MainCommand::LeafnodeSubscribe { leafnode_id: id, interest }),
+            (Link::Leafnode { .. }, RouteCommand::Unsubscribe(interest)) => Some(MainCommand::LeafnodeUnsubscribe { leafnode_id: id, interest }),
+            (Link::Leafnode { .. }, RouteCommand::Msg(message)) => Some(MainCommand::LeafnodePublish { leafnode_id: id, message }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Link::Route { .. } => write!(f, "route"),
+            Link::Leafnode { .. } => write!(f, "leaf node"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD};
+    use test_case::test_case;
+
+    fn message(reply_to: Option<&str>, headers: Option<&'static str>, msg: &'static str, queue_groups: &[&str]) -> RouteCommand {
+        RouteCommand::Msg(RoutedMessage {
+            account: "$G".to_string(),
+            subject: "foo".to_string(),
+            reply_to: reply_to.map(str::to_string),
+            headers: headers.map(Bytes::from),
+            msg: Bytes::from(msg),
+            queue_groups: queue_groups.iter().map(|queue_group| queue_group.to_string()).collect(),
+        })
+    }
+
+    fn leaf_message(reply_to: Option<&str>, headers: Option<&'static str>) -> RoutedMessage {
+        match message(reply_to, headers, "hello", &[]) {
+            RouteCommand::Msg(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test_case("PING\r\n", RouteCommand::Ping; "ping")]
+    #[test_case("PONG\r\n", RouteCommand::Pong; "pong")]
+    #[test_case("CONNECT {\"user\":\"route\",\"pass\":\"s3cret\"}\r\n", RouteCommand::Connect(RouteConnectOpts { user: "route".to_string(), pass: "s3cret".to_string() }); "connect")]
+    #[test_case("INFO {\"server_id\":\"S1\",\"unknown\":1}\r\n", RouteCommand::Info(RouteInfo { server_id: "S1".to_string(), ..Default::default() }); "info")]
+    #[test_case("INFO {\"server_id\":\"S1\",\"route_url\":\"a:6222\",\"routes\":[\"b:6222\"]}\r\n", RouteCommand::Info(RouteInfo { server_id: "S1".to_string(), route_url: "a:6222".to_string(), routes: vec!["b:6222".to_string()], ..Default::default() }); "info with routes")]
+    #[test_case("RS+ $G foo.*\r\n", RouteCommand::Subscribe(interest("$G", "foo.*", None)); "subscribe")]
+    #[test_case("RS+ $G foo workers 1\r\n", RouteCommand::Subscribe(interest("$G", "foo", Some("workers"))); "subscribe queue group")]
+    #[test_case("RS- team_a foo\r\n", RouteCommand::Unsubscribe(interest("team_a", "foo", None)); "unsubscribe")]
+    #[test_case("RS- $G foo workers\r\n", RouteCommand::Unsubscribe(interest("$G", "foo", Some("workers"))); "unsubscribe queue group")]
+    #[test_case("RMSG $G foo 5\r\nhello\r\n", message(None, None, "hello", &[]); "rmsg")]
+    #[test_case("RMSG $G foo reply 5\r\nhello\r\n", message(Some("reply"), None, "hello", &[]); "rmsg with reply")]
+    #[test_case("RMSG $G foo + reply q1 q2 5\r\nhello\r\n", message(Some("reply"), None, "hello", &["q1", "q2"]); "rmsg with reply and queue groups")]
+    #[test_case("RMSG $G foo | q1 0\r\n\r\n", message(None, None, "", &["q1"]); "rmsg with queue group and empty message")]
+    #[test_case("HMSG $G foo 12 17\r\nNATS/1.0\r\n\r\nhello\r\n", message(None, Some("NATS/1.0\r\n\r\n"), "hello", &[]); "hmsg")]
+    fn test_parse_ok(input: &str, expected: RouteCommand) {
+        let mut request = RouteRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
+        let mut start = 0;
+        let mut actual = RouteCommand::Noop;
+        while start < input.len() && actual == RouteCommand::Noop {
+            let (parsed, bytes_read) = request.parse(&input.as_bytes()[start..]);
+            actual = parsed.unwrap();
+            start += bytes_read + 1;
+        }
+        assert_eq!(expected, actual);
+    }
+
+    #[test_case("PUB foo 5\r\n", InvalidInput; "client command")]
+    #[test_case("INFO {\r\n", InvalidInput; "invalid info")]
+    #[test_case("CONNECT {\r\n", InvalidInput; "invalid connect")]
+    #[test_case("RS+ $G\r\n", InvalidInput; "subscribe without subject")]
+    #[test_case("RMSG $G foo\r\n", InvalidInput; "rmsg without size")]
+    #[test_case("RMSG $G foo x\r\n", InvalidInput; "rmsg size not a number")]
+    #[test_case("HMSG $G foo 20 10\r\n", InvalidInput; "hmsg header larger than total")]
+    #[test_case("RMSG $G foo 2000000\r\n", MaxPayloadExceeded; "rmsg too large")]
+    fn test_parse_fail(input: &str, expected: ParseError) {
+        let mut request = RouteRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
+        assert_eq!(expected, request.parse(input.as_bytes()).0.unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_message_too_long() {
+        let mut request = RouteRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
+        let input = b"RMSG $G foo 3\r\ntoolong\r\n";
+        let (parsed, bytes_read) = request.parse(input);
+        assert_eq!(RouteCommand::Noop, parsed.unwrap());
+        assert_eq!(InvalidInput, request.parse(&input[bytes_read + 1..]).0.unwrap_err());
+    }
+
+    #[test_case("LS+ foo.*\r\n", RouteCommand::Subscribe(interest("team_a", "foo.*", None)); "leaf subscribe")]
+    #[test_case("LS+ foo workers 1\r\n", RouteCommand::Subscribe(interest("team_a", "foo", Some("workers"))); "leaf subscribe queue group")]
+    #[test_case("LS- foo\r\n", RouteCommand::Unsubscribe(interest("team_a", "foo", None)); "leaf unsubscribe")]
+    #[test_case("LMSG foo reply 5\r\nhello\r\n", RouteCommand::Msg(RoutedMessage { account: "team_a".to_string(), ..leaf_message(Some("reply"), None) }); "lmsg with reply")]
+    #[test_case("HMSG foo 12 17\r\nNATS/1.0\r\n\r\nhello\r\n", RouteCommand::Msg(RoutedMessage { account: "team_a".to_string(), ..leaf_message(None, Some("NATS/1.0\r\n\r\n")) }); "leaf hmsg")]
+    fn test_parse_leafnode_ok(input: &str, expected: RouteCommand) {
+        let mut request = RouteRequest::leafnode(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE, "team_a".to_string());
+        let (parsed, bytes_read) = request.parse(input.as_bytes());
+        let parsed = match parsed.unwrap() {
+            RouteCommand::Noop => request.parse(&input.as_bytes()[bytes_read + 1..]).0.unwrap(),
+            parsed => parsed,
+        };
+        assert_eq!(expected, parsed);
+    }
+
+    #[test_case("RS+ $G foo\r\n"; "route subscribe")]
+    #[test_case("RMSG $G foo 5\r\n"; "route message")]
+    fn test_parse_leafnode_fail(input: &str) {
+        let mut request = RouteRequest::leafnode(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE, "team_a".to_string());
+        assert_eq!(InvalidInput, request.parse(input.as_bytes()).0.unwrap_err());
+    }
+
+    #[test_case(RouteCommand::Subscribe(interest("$G", "foo.>", Some("workers"))); "subscribe")]
+    #[test_case(RouteCommand::Unsubscribe(interest("$G", "foo.>", None)); "unsubscribe")]
+    #[test_case(message(Some("reply"), Some("NATS/1.0\r\n\r\n"), "he\r\nllo", &["q1"]); "hmsg with reply and queue group")]
+    #[test_case(message(None, None, "hello", &["q1", "q2"]); "rmsg with queue groups")]
+    fn test_encode_parse(command: RouteCommand) {
+        let mut request = RouteRequest::new(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE);
+        let encoded = command.encode();
+        let (parsed, bytes_read) = request.parse(&encoded);
+        let parsed = match parsed.unwrap() {
+            RouteCommand::Noop => request.parse(&encoded[bytes_read + 1..]).0.unwrap(),
+            parsed => parsed,
+        };
+        assert_eq!(command, parsed);
+    }
+
+    #[test_case(RouteCommand::Subscribe(interest("$G", "foo.>", Some("workers"))), "LS+ foo.> workers 1\r\n"; "subscribe")]
+    #[test_case(RouteCommand::Unsubscribe(interest("$G", "foo.>", None)), "LS- foo.>\r\n"; "unsubscribe")]
+    #[test_case(message(Some("reply"), None, "hello", &["q1"]), "LMSG foo + reply q1 5\r\nhello\r\n"; "lmsg with reply and queue group")]
+    fn test_encode_leafnode(command: RouteCommand, expected: &str) {
+        assert_eq!(expected.as_bytes(), command.encode_leafnode());
+    }
+
+    #[test_case("0.0.0.0:6222", "10.0.0.2:6222"; "unspecified ipv4")]
+    #[test_case("[::]:6222", "10.0.0.2:6222"; "unspecified ipv6")]
+    #[test_case("127.0.0.1:6222", "127.0.0.1:6222"; "specified")]
+    #[test_case("server1:6222", "server1:6222"; "host name")]
+    #[test_case("", ""; "empty")]
+    fn test_resolve_unspecified(url: &str, expected: &str) {
+        let info = RouteInfo { route_url: url.to_string(), client_url: url.to_string(), ..Default::default() };
+        let info = info.resolve_unspecified("10.0.0.2".parse().unwrap());
+        assert_eq!(expected, info.route_url);
+        assert_eq!(expected, info.client_url);
+    }
+}
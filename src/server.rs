@@ -1,57 +1,204 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU32};
+use std::sync::Arc;
 use log::{info, warn};
+use rand::distributions::Uniform;
+use rand::Rng;
+use serde::Serialize;
 use tokio::sync;
 use tokio::sync::{RwLock};
 use tokio::sync::mpsc::{Receiver, Sender};
+use crate::account::{Imports, ServiceReply};
 use crate::commands::MainCommand;
+use crate::tls::build_acceptor;
+use tokio_rustls::TlsAcceptor;
+use crate::config::{Config, Listener, Permissions};
+use crate::handlers::ClientAddr;
+use crate::parser::ClientConnectOpts;
+use crate::leafnode::Leafnode;
+use crate::route::{Interest, Route};
+
+// protocol version 1 lets clients receive async INFO updates
+const PROTOCOL_VERSION: u32 = 1;
+const SERVER_ID_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+const SERVER_ID_LENGTH: usize = 22;
+// account of clients that were not assigned one, same name as in nats-server
+pub const GLOBAL_ACCOUNT: &str = "$G";
+
+// subscription maps are scoped by account name, so each account has its own subject space
+pub type AccountMap<K, V> = HashMap<String, HashMap<K, HashSet<V>>>;
+// sids are chosen by the clients, so a subscription is only unique together with its client id
+pub type SubscriptionKey = (u32, String);
 
 pub struct Server {
+    pub config: Config,
+    pub server_id: String,
     pub client_id: AtomicU32,
 
-    pub subscription_subject_to_id: RwLock<HashMap<String, HashSet<String>>>,
-    pub subscription_id_to_subject: RwLock<HashMap<String, HashSet<String>>>,
+    pub subscription_subject_to_id: RwLock<AccountMap<String, SubscriptionKey>>,
+    pub subscription_id_to_subject: RwLock<AccountMap<SubscriptionKey, String>>,
 
-    pub subscription_id_to_client_id: RwLock<HashMap<String, HashSet<u32>>>,
     pub client_id_to_subscription_id: RwLock<HashMap<u32, HashSet<String>>>,
+    // queue group name keyed by (client id, subscription id)
+    pub subscription_queue_group: RwLock<HashMap<(u32, String), String>>,
+    // messages delivered and the optional UNSUB max_msgs limit keyed by (client id, subscription id)
+    pub subscription_delivered: RwLock<HashMap<(u32, String), u32>>,
+    pub subscription_max_msgs: RwLock<HashMap<(u32, String), u32>>,
+    // number of local subscriptions behind each interest, kept up to date on every SUB and UNSUB
+    pub client_interest: RwLock<HashMap<Interest, usize>>,
 
     pub clients_tx: RwLock<HashMap<u32, (Sender<MainCommand>, ClientState)>>,
     pub main_tx: Sender<MainCommand>,
+
+    // stream and service imports between accounts, resolved from config at startup
+    pub imports: Imports,
+    // pending replies of forwarded service requests keyed by the `_R_.` reply subject
+    pub service_replies: RwLock<HashMap<String, ServiceReply>>,
+    // TLS acceptor of each listener, in the same order as the listeners in the config
+    pub tls_acceptors: Vec<Option<TlsAcceptor>>,
+
+    // routes to the other servers of the cluster keyed by their server id
+    pub routes: RwLock<HashMap<String, Route>>,
+    // interest received from each route keyed by route id
+    pub route_interest: RwLock<HashMap<u32, HashSet<Interest>>>,
+    // interest of the local clients and leaf nodes as last sent to the routes
+    pub advertised_interest: RwLock<HashSet<Interest>>,
+    // route addresses this server connects to, configured or discovered
+    pub solicited_routes: RwLock<HashSet<String>>,
+
+    // leaf node connections in both directions keyed by leaf node id
+    pub leafnodes: RwLock<HashMap<u32, Leafnode>>,
+}
+
+// INFO block sent to clients, see https://docs.nats.io/reference/reference-protocols/nats-protocol#info
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub server_name: String,
+    pub version: String,
+    pub proto: u32,
+    pub go: String,
+    pub host: String,
+    pub port: u16,
+    pub headers: bool,
+    pub max_payload: usize,
+    pub auth_required: bool,
+    pub tls_required: bool,
+    pub tls_verify: bool,
+    // TLS is offered but plaintext clients are accepted too
+    pub tls_available: bool,
+    pub client_id: u32,
+    pub client_ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    // client addresses of the servers in the cluster
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connect_urls: Vec<String>,
 }
 
 #[derive(Default)]
 pub struct ClientState {
     pub connected: bool,
-    pub verbose: bool,
+    pub connect_opts: ClientConnectOpts,
+    pub permissions: Option<Permissions>,
+    pub account: Option<String>,
+}
+
+impl ClientState {
+    pub fn account(&self) -> &str {
+        self.account.as_deref().unwrap_or(GLOBAL_ACCOUNT)
+    }
 }
 
 impl Server {
-    pub fn new() -> (Server, Receiver<MainCommand>) {
+    pub fn new(config: Config) -> (Server, Receiver<MainCommand>) {
         let (tx, rx) = sync::mpsc::channel(100);
+        let imports = Imports::new(&config.accounts);
+        let tls_acceptors = config.listeners.iter()
+            .map(|listener| listener.tls.as_ref().map(|tls| build_acceptor(tls).expect("Unable to load TLS certificates")))
+            .collect();
 
         (Server {
+            config,
+            server_id: generate_server_id(),
             client_id: AtomicU32::new(0),
             subscription_subject_to_id: RwLock::new(HashMap::new()),
             subscription_id_to_subject: RwLock::new(HashMap::new()),
-            subscription_id_to_client_id: RwLock::new(HashMap::new()),
             client_id_to_subscription_id: RwLock::new(HashMap::new()),
+            subscription_queue_group: RwLock::new(HashMap::new()),
+            subscription_delivered: RwLock::new(HashMap::new()),
+            subscription_max_msgs: RwLock::new(HashMap::new()),
+            client_interest: RwLock::new(HashMap::new()),
             clients_tx: RwLock::new(HashMap::new()),
             main_tx: tx,
+            imports,
+            service_replies: RwLock::new(HashMap::new()),
+            tls_acceptors,
+            routes: RwLock::new(HashMap::new()),
+            route_interest: RwLock::new(HashMap::new()),
+            advertised_interest: RwLock::new(HashSet::new()),
+            solicited_routes: RwLock::new(HashSet::new()),
+            leafnodes: RwLock::new(HashMap::new()),
         }, rx)
     }
 
-    pub async fn process_rx(&self, mut rx: Receiver<MainCommand>) {
+    // `listener` is the client listener the client connected to, other transports have no TLS
+    pub async fn server_info(&self, client_id: u32, addr: Option<ClientAddr>, nonce: Option<String>, listener: Option<&Listener>) -> ServerInfo {
+        let tls = listener.and_then(|listener| listener.tls.as_ref());
+        ServerInfo {
+            server_id: self.server_id.clone(),
+            server_name: self.config.server_name.clone().unwrap_or_else(|| self.server_id.clone()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            proto: PROTOCOL_VERSION,
+            go: "rust".to_string(),
+            host: addr.map(|addr| addr.local.ip().to_string()).unwrap_or_default(),
+            port: addr.map(|addr| addr.local.port()).unwrap_or_default(),
+            headers: true,
+            max_payload: self.config.max_payload,
+            auth_required: self.auth_required() && !listener.is_some_and(|listener| listener.no_auth),
+            tls_required: tls.is_some_and(|tls| !tls.allow_non_tls),
+            tls_verify: tls.is_some_and(|tls| tls.requires_client_cert()),
+            tls_available: tls.is_some(),
+            client_id,
+            client_ip: addr.map(|addr| addr.peer.ip().to_string()).unwrap_or_default(),
+            nonce,
+            connect_urls: self.connect_urls().await,
+        }
+    }
+
+    pub fn auth_required(&self) -> bool {
+        self.config.authorization.as_ref().is_some_and(|authorization| authorization.is_required())
+    }
+
+    pub fn nonce_required(&self) -> bool {
+        self.config.authorization.as_ref().is_some_and(|authorization| authorization.is_nonce_required())
+    }
+
+    pub async fn process_rx(self: &Arc<Self>, mut rx: Receiver<MainCommand>) {
         while let Some(command) = rx.recv().await {
             info!("received command: {:?}", command);
             match command {
                 MainCommand::Noop => {}
                 MainCommand::InitClient { client_id, tx } => self.process_init_client(client_id, tx).await,
-                MainCommand::Connect { client_id, client_connect_opts } => self.process_connect(client_id, client_connect_opts).await,
+                MainCommand::Connect { client_id, client_connect_opts, client_auth, processed } => self.process_connect(client_id, client_connect_opts, client_auth, processed).await,
                 MainCommand::Disconnect { client_id } => self.process_disconnect(client_id).await,
-                MainCommand::Subscribe { client_id, subject, subscription_id } => self.process_subscribe(client_id, subject, subscription_id).await,
-                MainCommand::Unsubscribe { client_id, subscription_id } => self.process_unsubscribe(client_id, subscription_id).await,
-                MainCommand::Publish { subject, msg } => self.process_publish(subject, msg).await,
+                MainCommand::Subscribe { client_id, subject, queue_group, subscription_id } => self.process_subscribe(client_id, subject, queue_group, subscription_id).await,
+                MainCommand::Unsubscribe { client_id, subscription_id, max_msgs } => self.process_unsubscribe(client_id, subscription_id, max_msgs).await,
+                MainCommand::Publish { client_id, subject, reply_to, headers, msg } => self.process_publish(client_id, subject, reply_to, headers, msg).await,
                 MainCommand::PublishedMessage { .. } => warn!("server received published message"),
+                MainCommand::AuthenticationExpired => warn!("server received authentication expired"),
+                MainCommand::AsyncInfo { .. } => warn!("server received async info"),
+                MainCommand::RouteConnect { route_id, info, solicited, tx, processed } => self.process_route_connect(route_id, info, solicited, tx, processed).await,
+                MainCommand::RouteInfo { route_id, info } => self.process_route_info(route_id, info).await,
+                MainCommand::RouteDisconnect { route_id } => self.process_route_disconnect(route_id).await,
+                MainCommand::RouteSubscribe { route_id, interest } => self.process_route_subscribe(route_id, interest).await,
+                MainCommand::RouteUnsubscribe { route_id, interest } => self.process_route_unsubscribe(route_id, interest).await,
+                MainCommand::RoutePublish { route_id, message } => self.process_route_publish(route_id, message).await,
+                MainCommand::LeafnodeConnect { leafnode_id, account, solicited, info, tx, processed } => self.process_leafnode_connect(leafnode_id, account, solicited, info, tx, processed).await,
+                MainCommand::LeafnodeDisconnect { leafnode_id } => self.process_leafnode_disconnect(leafnode_id).await,
+                MainCommand::LeafnodeSubscribe { leafnode_id, interest } => self.process_leafnode_subscribe(leafnode_id, interest).await,
+                MainCommand::LeafnodeUnsubscribe { leafnode_id, interest } => self.process_leafnode_unsubscribe(leafnode_id, interest).await,
+                MainCommand::LeafnodePublish { leafnode_id, message } => self.process_leafnode_publish(leafnode_id, message).await,
                 MainCommand::ShutDown => {
                     self.process_shutdown().await;
                     break;
@@ -61,3 +208,92 @@ impl Server {
         info!("stopping process_rx");
     }
 }
+
+// generated once on start up and kept for the lifetime of the process
+fn generate_server_id() -> String {
+    rand::thread_rng()
+        .sample_iter(Uniform::from(0..SERVER_ID_CHARS.len()))
+        .take(SERVER_ID_LENGTH)
+        .map(|i| SERVER_ID_CHARS[i] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Tls;
+
+    fn server(config: &str) -> Server {
+        let config = format!("listeners = [{{ address = \"127.0.0.1:4222\" }}]\n{}", config);
+        Server::new(toml::from_str(&config).unwrap()).0
+    }
+
+    fn addr() -> ClientAddr {
+        ClientAddr { local: "127.0.0.1:4222".parse().unwrap(), peer: "127.0.0.2:50000".parse().unwrap() }
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let server = server("max_payload = 1024");
+        let info = server.server_info(7, Some(addr()), Some("nonce".to_string()), None).await;
+        assert_eq!(SERVER_ID_LENGTH, info.server_id.len());
+        assert_eq!(info.server_id, info.server_name);
+        assert_eq!(env!("CARGO_PKG_VERSION"), info.version);
+        assert_eq!(PROTOCOL_VERSION, info.proto);
+        assert_eq!(("127.0.0.1", 4222), (info.host.as_str(), info.port));
+        assert_eq!((7, "127.0.0.2"), (info.client_id, info.client_ip.as_str()));
+        assert_eq!(1024, info.max_payload);
+        assert!(info.headers);
+        assert!(!info.auth_required);
+        assert_eq!(Some("nonce".to_string()), info.nonce);
+        assert_eq!(vec!["127.0.0.1:4222".to_string()], info.connect_urls);
+    }
+
+    #[tokio::test]
+    async fn test_server_info_serialization() {
+        let server = server("server_name = \"n1\"");
+        let info = serde_json::to_value(server.server_info(1, None, None, None).await).unwrap();
+        assert_eq!("n1", info["server_name"]);
+        assert_eq!("rust", info["go"]);
+        assert!(info.get("nonce").is_none());
+    }
+
+    fn listener(tls: Option<Tls>, no_auth: bool) -> Listener {
+        Listener { address: "127.0.0.1:4443".to_string(), tls, no_auth }
+    }
+
+    fn tls(verify: bool, allow_non_tls: bool) -> Tls {
+        Tls {
+            cert_file: "server.pem".to_string(),
+            key_file: "server-key.pem".to_string(),
+            ca_file: verify.then(|| "ca.pem".to_string()),
+            verify,
+            verify_and_map: false,
+            allow_non_tls,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_info_no_auth_listener() {
+        let server = server("[authorization]\nuser = \"admin\"\npassword = \"secret\"");
+        assert!(server.server_info(1, None, None, None).await.auth_required);
+        assert!(server.server_info(1, None, None, Some(&listener(None, false))).await.auth_required);
+        assert!(!server.server_info(1, None, None, Some(&listener(None, true))).await.auth_required);
+    }
+
+    #[tokio::test]
+    async fn test_server_info_tls_listener() {
+        let server = server("");
+        let info = server.server_info(1, None, None, Some(&listener(None, false))).await;
+        assert_eq!((false, false, false), (info.tls_required, info.tls_verify, info.tls_available));
+
+        let info = server.server_info(1, None, None, Some(&listener(Some(tls(false, false)), false))).await;
+        assert_eq!((true, false, true), (info.tls_required, info.tls_verify, info.tls_available));
+
+        let info = server.server_info(1, None, None, Some(&listener(Some(tls(true, false)), false))).await;
+        assert_eq!((true, true, true), (info.tls_required, info.tls_verify, info.tls_available));
+
+        let info = server.server_info(1, None, None, Some(&listener(Some(tls(false, true)), false))).await;
+        assert_eq!((false, false, true), (info.tls_required, info.tls_verify, info.tls_available));
+    }
+}
@@ -0,0 +1,187 @@
+const TOKEN_SEPARATOR: char = '.';
+const SINGLE_WILDCARD: &str = "*";
+const FULL_WILDCARD: &str = ">";
+
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty() && !token.chars().any(|c| c.is_whitespace())
+}
+
+// subscription subjects may contain `*` and `>` as full tokens, `>` only as the last token
+pub fn is_valid_subscription_subject(subject: &str) -> bool {
+    let mut tokens = subject.split(TOKEN_SEPARATOR).peekable();
+    while let Some(token) = tokens.next() {
+        if !is_valid_token(token) {
+            return false;
+        }
+        if token == FULL_WILDCARD && tokens.peek().is_some() {
+            return false;
+        }
+    }
+    true
+}
+
+// publish subjects must be literal, wildcards are only meaningful for subscriptions
+pub fn is_valid_publish_subject(subject: &str) -> bool {
+    subject.split(TOKEN_SEPARATOR)
+        .all(|token| is_valid_token(token) && token != SINGLE_WILDCARD && token != FULL_WILDCARD)
+}
+
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern_tokens = pattern.split(TOKEN_SEPARATOR);
+    let mut subject_tokens = subject.split(TOKEN_SEPARATOR);
+
+    loop {
+        match (pattern_tokens.next(), subject_tokens.next()) {
+            (Some(FULL_WILDCARD), Some(_)) => return true,
+            (Some(SINGLE_WILDCARD), Some(_)) => {}
+            (Some(p), Some(s)) => {
+                if p != s {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// true when every subject matched by `subject` is also matched by `pattern`
+pub fn subject_is_subset(subject: &str, pattern: &str) -> bool {
+    let mut subject_tokens = subject.split(TOKEN_SEPARATOR);
+    let mut pattern_tokens = pattern.split(TOKEN_SEPARATOR);
+
+    loop {
+        match (subject_tokens.next(), pattern_tokens.next()) {
+            (Some(_), Some(FULL_WILDCARD)) => return true,
+            (Some(FULL_WILDCARD), Some(_)) => return false,
+            (Some(SINGLE_WILDCARD), Some(SINGLE_WILDCARD)) => {}
+            (Some(SINGLE_WILDCARD), Some(_)) => return false,
+            (Some(_), Some(SINGLE_WILDCARD)) => {}
+            (Some(s), Some(p)) => {
+                if s != p {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// maps `subject` matched by `from` onto `to`, wildcard tokens of `to` are filled
+// in order with the tokens matched by the wildcards of `from`
+pub fn transform_subject(from: &str, to: &str, subject: &str) -> Option<String> {
+    let mut captures: Vec<String> = vec![];
+    let mut from_tokens = from.split(TOKEN_SEPARATOR);
+    let mut subject_tokens = subject.split(TOKEN_SEPARATOR);
+    loop {
+        match (from_tokens.next(), subject_tokens.next()) {
+            (Some(FULL_WILDCARD), Some(s)) => {
+                let rest: Vec<&str> = std::iter::once(s).chain(subject_tokens.by_ref()).collect();
+                captures.push(rest.join("."));
+                break;
+            }
+            (Some(SINGLE_WILDCARD), Some(s)) => captures.push(s.to_string()),
+            (Some(f), Some(s)) if f == s => {}
+            (None, None) => break,
+            _ => return None,
+        }
+    }
+
+    let mut captures = captures.into_iter();
+    let tokens = to.split(TOKEN_SEPARATOR)
+        .map(|token| match token {
+            SINGLE_WILDCARD | FULL_WILDCARD => captures.next(),
+            _ => Some(token.to_string()),
+        })
+        .collect::<Option<Vec<String>>>()?;
+    Some(tokens.join("."))
+}
+
+// true when both subjects have the same wildcards in the same order, required to transform between them
+pub fn has_same_wildcards(a: &str, b: &str) -> bool {
+    let is_wildcard = |token: &&str| *token == SINGLE_WILDCARD || *token == FULL_WILDCARD;
+    a.split(TOKEN_SEPARATOR).filter(is_wildcard).eq(b.split(TOKEN_SEPARATOR).filter(is_wildcard))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("foo", true; "single token")]
+    #[test_case("foo.bar", true; "multiple tokens")]
+    #[test_case("foo.*", true; "single wildcard")]
+    #[test_case("*.bar.*", true; "multiple single wildcards")]
+    #[test_case("foo.>", true; "full wildcard")]
+    #[test_case(">", true; "only full wildcard")]
+    #[test_case("foo*.bar", true; "asterisk inside token is literal")]
+    #[test_case("", false; "empty")]
+    #[test_case("foo.", false; "trailing separator")]
+    #[test_case(".foo", false; "leading separator")]
+    #[test_case("foo..bar", false; "empty token")]
+    #[test_case("foo.>.bar", false; "full wildcard not last")]
+    fn test_is_valid_subscription_subject(subject: &str, expected: bool) {
+        assert_eq!(expected, is_valid_subscription_subject(subject));
+    }
+
+    #[test_case("foo", true; "single token")]
+    #[test_case("foo.bar", true; "multiple tokens")]
+    #[test_case("foo*.bar", true; "asterisk inside token is literal")]
+    #[test_case("foo.*", false; "single wildcard")]
+    #[test_case("foo.>", false; "full wildcard")]
+    #[test_case("foo..bar", false; "empty token")]
+    fn test_is_valid_publish_subject(subject: &str, expected: bool) {
+        assert_eq!(expected, is_valid_publish_subject(subject));
+    }
+
+    #[test_case("foo.bar", "foo.bar", true; "literal match")]
+    #[test_case("foo.bar", "foo.baz", false; "literal mismatch")]
+    #[test_case("foo.*", "foo.bar", true; "single wildcard")]
+    #[test_case("foo.*", "foo.bar.baz", false; "single wildcard matches one token only")]
+    #[test_case("foo.*", "foo", false; "single wildcard requires a token")]
+    #[test_case("*.bar", "foo.bar", true; "leading single wildcard")]
+    #[test_case("foo.>", "foo.bar", true; "full wildcard one token")]
+    #[test_case("foo.>", "foo.bar.baz", true; "full wildcard many tokens")]
+    #[test_case("foo.>", "foo", false; "full wildcard requires a token")]
+    #[test_case(">", "foo.bar", true; "only full wildcard")]
+    #[test_case("foo.*.>", "foo.bar.baz", true; "mixed wildcards")]
+    #[test_case("foo.bar", "foo", false; "subject shorter than pattern")]
+    fn test_subject_matches(pattern: &str, subject: &str, expected: bool) {
+        assert_eq!(expected, subject_matches(pattern, subject));
+    }
+
+    #[test_case("foo.bar", "foo.bar", true; "literal equal")]
+    #[test_case("foo.bar", "foo.*", true; "literal in single wildcard")]
+    #[test_case("foo.bar.baz", "foo.>", true; "literal in full wildcard")]
+    #[test_case("foo.*", "foo.*", true; "single wildcard equal")]
+    #[test_case("foo.*", "foo.>", true; "single wildcard in full wildcard")]
+    #[test_case("foo.>", "foo.>", true; "full wildcard equal")]
+    #[test_case("foo.*", "foo.bar", false; "single wildcard not in literal")]
+    #[test_case("foo.>", "foo.*", false; "full wildcard not in single wildcard")]
+    #[test_case(">", "foo.>", false; "everything not in prefix")]
+    #[test_case("foo", "foo.>", false; "full wildcard requires a token")]
+    #[test_case("bar.baz", "foo.>", false; "different prefix")]
+    fn test_subject_is_subset(subject: &str, pattern: &str, expected: bool) {
+        assert_eq!(expected, subject_is_subset(subject, pattern));
+    }
+
+    #[test_case("events.>", "a.events.>", "events.new.order", Some("a.events.new.order"); "prefix full wildcard")]
+    #[test_case("events.*", "a.*", "events.new", Some("a.new"); "single wildcard")]
+    #[test_case("orders.*.*", "orders.*.*.copy", "orders.eu.new", Some("orders.eu.new.copy"); "wildcards in order")]
+    #[test_case("help", "team_a.help", "help", Some("team_a.help"); "literal")]
+    #[test_case("events.*", "a.*", "other.new", None; "no match")]
+    #[test_case("events.*", "a.*.*", "events.new", None; "not enough wildcards")]
+    fn test_transform_subject(from: &str, to: &str, subject: &str, expected: Option<&str>) {
+        assert_eq!(expected.map(str::to_string), transform_subject(from, to, subject));
+    }
+
+    #[test_case("events.>", "a.events.>", true; "same full wildcard")]
+    #[test_case("a.*.b.>", "*.x.>", true; "same wildcards in order")]
+    #[test_case("help", "other.help", true; "no wildcards")]
+    #[test_case("events.*", "a.>", false; "different wildcards")]
+    #[test_case("events.*", "a", false; "missing wildcard")]
+    fn test_has_same_wildcards(a: &str, b: &str, expected: bool) {
+        assert_eq!(expected, has_same_wildcards(a, b));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use rustls_pemfile::{certs, private_key};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+use crate::config::Tls;
+
+// first byte of a TLS handshake record, used to tell TLS from plaintext clients
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+impl Tls {
+    pub fn requires_client_cert(&self) -> bool {
+        self.verify || self.verify_and_map
+    }
+}
+
+pub fn build_acceptor(tls: &Tls) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let builder = match (&tls.ca_file, tls.requires_client_cert()) {
+        (Some(ca_file), true) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        (None, true) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "ca_file is required to verify client certificates")),
+        _ => builder.with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(load_certs(&tls.cert_file)?, load_private_key(&tls.key_file)?)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key found in {}", path)))
+}
+
+// names a client certificate can be mapped to a user by: the subject, then email and DNS alternative names
+pub fn cert_identities(cert: &CertificateDer) -> Vec<String> {
+    let Ok((_, cert)) = X509Certificate::from_der(cert) else {
+        return vec![];
+    };
+    let mut identities = vec![cert.subject().to_string()];
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::RFC822Name(name) | GeneralName::DNSName(name) => identities.push(name.to_string()),
+                _ => {}
+            }
+        }
+    }
+    identities
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    fn write_temp(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("challenge_nats_tls_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn self_signed(common_name: &str) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        let cert = params.self_signed(&key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn tls(cert_file: String, key_file: String, ca_file: Option<String>, verify: bool) -> Tls {
+        Tls { cert_file, key_file, ca_file, verify, verify_and_map: false, allow_non_tls: false }
+    }
+
+    #[test]
+    fn test_build_acceptor() {
+        let (cert, key) = self_signed("server");
+        let tls = tls(write_temp("server.pem", &cert), write_temp("server-key.pem", &key), None, false);
+        assert!(build_acceptor(&tls).is_ok());
+    }
+
+    #[test]
+    fn test_build_acceptor_with_client_verification() {
+        let (cert, key) = self_signed("verify");
+        let cert_file = write_temp("verify.pem", &cert);
+        let tls = tls(cert_file.clone(), write_temp("verify-key.pem", &key), Some(cert_file), true);
+        assert!(build_acceptor(&tls).is_ok());
+    }
+
+    #[test]
+    fn test_build_acceptor_verify_without_ca() {
+        let (cert, key) = self_signed("noca");
+        let tls = tls(write_temp("noca.pem", &cert), write_temp("noca-key.pem", &key), None, true);
+        assert!(build_acceptor(&tls).is_err());
+    }
+
+    #[test]
+    fn test_build_acceptor_missing_key() {
+        let (cert, _) = self_signed("nokey");
+        let cert_file = write_temp("nokey.pem", &cert);
+        let tls = tls(cert_file.clone(), cert_file, None, false);
+        assert!(build_acceptor(&tls).is_err());
+    }
+
+    #[test]
+    fn test_cert_identities() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["alice.example.com".to_string()]).unwrap();
+        params.subject_alt_names.push(SanType::Rfc822Name("alice@example.com".try_into().unwrap()));
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.distinguished_name.push(DnType::OrganizationName, "Acme");
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(vec!["CN=alice, O=Acme", "alice.example.com", "alice@example.com"], cert_identities(cert.der()));
+    }
+}
@@ -0,0 +1,149 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+use crate::handlers::ClientAddr;
+use crate::server::Server;
+
+const BRIDGE_BUFFER_SIZE: usize = 4096;
+
+impl Server {
+    // the NATS protocol is carried in WebSocket frames, the frames are bridged to an in-memory
+    // stream so the connection is handled like any other client
+    pub async fn handle_websocket(&self, socket: TcpStream) {
+        let addr = match (socket.local_addr(), socket.peer_addr()) {
+            (Ok(local), Ok(peer)) => ClientAddr { local, peer },
+            (Err(e), _) | (_, Err(e)) => {
+                error!("error handling websocket connection: {}", e);
+                return;
+            }
+        };
+        let config = websocket_config(self.config.max_payload, self.config.max_control_line);
+        let websocket = match accept_async_with_config(socket, Some(config)).await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                error!("websocket handshake failed for {}: {}", addr.peer, e);
+                return;
+            }
+        };
+
+        let (client, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+        tokio::join!(self.handle(client, Some(addr)), bridge_websocket(websocket, bridge));
+        debug!("websocket connection from {} closed", addr.peer);
+    }
+}
+
+// frames are buffered before the protocol parser sees them, so they are limited to the largest
+// command a client may send
+fn websocket_config(max_payload: usize, max_control_line: usize) -> WebSocketConfig {
+    let max_size = max_payload + max_control_line;
+    WebSocketConfig {
+        max_message_size: Some(max_size),
+        max_frame_size: Some(max_size),
+        ..Default::default()
+    }
+}
+
+async fn bridge_websocket(websocket: WebSocketStream<TcpStream>, bridge: DuplexStream) {
+    let (mut ws_tx, mut ws_rx) = websocket.split();
+    let (mut reader, mut writer) = tokio::io::split(bridge);
+    let mut buffer = [0; BRIDGE_BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            // client to server, binary and text frames both carry protocol bytes
+            frame = ws_rx.next() => {
+                let data = match frame {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(text))) => text.into_bytes(),
+                    Some(Ok(Message::Close(_))) | None => break,
+                    // ping and pong frames are answered by tungstenite
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        debug!("error reading websocket frame: {}", e);
+                        break;
+                    }
+                };
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+
+            // server to client
+            read = reader.read(&mut buffer) => {
+                match read {
+                    Ok(0) | Err(_) => {
+                        let _ = ws_tx.close().await;
+                        break;
+                    }
+                    Ok(n) => {
+                        if let Err(e) = ws_tx.send(Message::Binary(buffer[..n].to_vec())).await {
+                            debug!("error writing websocket frame: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // lets the client handler see the end of the stream
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::client_async;
+
+    #[tokio::test]
+    async fn test_bridge_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            bridge_websocket(accept_async_with_config(socket, None).await.unwrap(), bridge).await;
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut websocket, _) = client_async(format!("ws://{}/", addr), socket).await.unwrap();
+
+        websocket.send(Message::Binary(b"PING\r\n".to_vec())).await.unwrap();
+        websocket.send(Message::Text("SUB foo 1\r\n".to_string())).await.unwrap();
+        let mut buffer = [0; 17];
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"PING\r\nSUB foo 1\r\n", &buffer);
+
+        client.write_all(b"PONG\r\n").await.unwrap();
+        assert_eq!(Some(Message::Binary(b"PONG\r\n".to_vec())), websocket.next().await.map(Result::unwrap));
+
+        // closing the client side ends the websocket connection
+        drop(client);
+        assert!(matches!(websocket.next().await, Some(Ok(Message::Close(_))) | None));
+    }
+
+    #[tokio::test]
+    async fn test_bridge_websocket_frame_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut client, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let config = websocket_config(16, 16);
+            bridge_websocket(accept_async_with_config(socket, Some(config)).await.unwrap(), bridge).await;
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut websocket, _) = client_async(format!("ws://{}/", addr), socket).await.unwrap();
+
+        websocket.send(Message::Binary(vec![b'x'; 64])).await.unwrap();
+        // the frame never reaches the client handler and the connection ends
+        let mut buffer = vec![];
+        client.read_to_end(&mut buffer).await.unwrap();
+        assert!(buffer.is_empty());
+    }
+}